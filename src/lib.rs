@@ -131,8 +131,18 @@ pub const ENOMEDIUM: i32 = 123; // No medium found
 pub const EMEDIUMTYPE: i32 = 124; // Wrong medium type
 
 #[allow(nonstandard_style)]
-use core::ffi::{c_char, c_int, c_void};
+use core::ffi::{c_char, c_int, c_ulong, c_void};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU16, AtomicU8, Ordering};
+use core::time::Duration;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CString;
+use std::io::{IoSlice, IoSliceMut};
+#[cfg(feature = "ipv6")]
+use std::net::SocketAddrV6;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::os::freertos::io::RawSocket;
+use std::sync::{Arc, Mutex, OnceLock};
 
 // Rust bindings for LwIP TCP/IP stack.
 include!("lwip-rs.rs");
@@ -142,14 +152,408 @@ extern "C" {
     static gnetif: netif;
 }
 
+// Look up a netif by its LwIP name: the two-character interface name followed by its number,
+// e.g. "st0".
+extern "C" {
+    #[link_name = "netif_find"]
+    fn lwip_netif_find(name: *const c_char) -> *mut netif;
+}
+
+// Start the tcpip thread, and run a function on it.
+extern "C" {
+    #[link_name = "tcpip_init"]
+    fn lwip_tcpip_init(initfunc: Option<extern "C" fn(*mut c_void)>, arg: *mut c_void);
+    fn tcpip_callback(function: extern "C" fn(*mut c_void), ctx: *mut c_void) -> err_t;
+}
+
+// ARP (LWIP_ARP, which the `arp` feature mirrors), used by gateway_reachable and arp_entries on
+// the tcpip thread. These are the LwIP 2.1 prototypes the bindings come from (lwip_poll is 2.1
+// only); 2.0 took a u8_t index in etharp_get_entry and returned s8_t from etharp_find_addr.
+#[cfg(feature = "arp")]
+extern "C" {
+    fn etharp_request(netif: *mut netif, ipaddr: *const ip4_addr_t) -> err_t;
+    fn etharp_find_addr(
+        netif: *mut netif,
+        ipaddr: *const ip4_addr_t,
+        eth_ret: *mut *mut c_void,
+        ip_ret: *mut *const ip4_addr_t,
+    ) -> ssize_t;
+    fn etharp_get_entry(
+        i: size_t,
+        ipaddr: *mut *mut ip4_addr_t,
+        netif: *mut *mut netif,
+        eth_ret: *mut *mut c_void,
+    ) -> c_int;
+}
+
+// DHCP client (LWIP_DHCP). True while the interface holds an address from a lease: bound,
+// renewing or rebinding.
+extern "C" {
+    fn dhcp_supplied_address(netif: *const netif) -> u8_t;
+    fn dhcp_start(netif: *mut netif) -> err_t;
+    fn dhcp_stop(netif: *mut netif);
+    fn netif_set_addr(
+        netif: *mut netif,
+        ipaddr: *const ip4_addr_t,
+        netmask: *const ip4_addr_t,
+        gw: *const ip4_addr_t,
+    );
+    fn err_to_errno(err: err_t) -> c_int;
+}
+
+// Head of LwIP's list of all interfaces, linked through netif.next.
+extern "C" {
+    static netif_list: *mut netif;
+}
+
+// LwIP's millisecond clock (provided by the sys_arch port). Wraps roughly every 49.7 days.
+extern "C" {
+    fn sys_now() -> u32_t;
+    fn sys_msleep(ms: u32_t);
+}
+
+// FreeRTOS task priority control, used by the opt-in socket priority boost. A null task handle
+// refers to the calling task. Needs INCLUDE_uxTaskPriorityGet and INCLUDE_vTaskPrioritySet, and
+// FreeRTOS 10.5+ with configUSE_MUTEXES for uxTaskBasePriorityGet.
+extern "C" {
+    fn uxTaskPriorityGet(xTask: *const c_void) -> c_ulong;
+    fn uxTaskBasePriorityGet(xTask: *const c_void) -> c_ulong;
+    fn vTaskPrioritySet(xTask: *mut c_void, uxNewPriority: c_ulong);
+    fn xTaskGetCurrentTaskHandle() -> *mut c_void;
+}
+
+// LwIP's core lock (LOCK_TCPIP_CORE/UNLOCK_TCPIP_CORE), which only exists when LwIP is built with
+// LWIP_TCPIP_CORE_LOCKING; enable this crate's `tcpip-core-locking` feature to match.
+#[cfg(feature = "tcpip-core-locking")]
+extern "C" {
+    static mut lock_tcpip_core: u8;
+    fn sys_mutex_lock(mutex: *mut c_void);
+    fn sys_mutex_unlock(mutex: *mut c_void);
+}
+
 // This constant not in LwIP Rust bindings, but needed by sys_common\net.rs
 pub const IPV6_MULTICAST_LOOP: i32 = 19; // Not supported in LwIP
 
+// Not in the LwIP Rust bindings either: _IOR('f', 127, unsigned long), bytes queued for reading.
+pub const FIONREAD: core::ffi::c_long = 0x4004667F;
+
+// The socket-API constants downstream code needs, in one place, so it can `use lwip::consts::*`
+// rather than reach into the generated bindings or redefine values (which differ between LwIP
+// configurations - AF_INET6, for one). Everything here is also exported at the crate root.
+pub mod consts {
+    // Address families
+    pub use crate::AF_INET; // IPv4
+    pub use crate::AF_INET6; // IPv6; equal to AF_UNSPEC when LwIP is built without LWIP_IPV6
+    pub use crate::AF_UNSPEC; // Any family (getaddrinfo hints)
+
+    // Socket types
+    pub use crate::SOCK_DGRAM; // Datagrams (UDP)
+    pub use crate::SOCK_RAW; // Raw IP packets (needs LWIP_RAW)
+    pub use crate::SOCK_STREAM; // Byte stream (TCP)
+
+    // Protocols, also the option levels for protocol-specific options
+    pub use crate::IPPROTO_ICMP; // ICMP
+    pub use crate::IPPROTO_ICMPV6; // ICMPv6
+    pub use crate::IPPROTO_IP; // IPv4 options (IP_*)
+    pub use crate::IPPROTO_IPV6; // IPv6 options (IPV6_*)
+    pub use crate::IPPROTO_RAW; // Raw IP
+    pub use crate::IPPROTO_TCP; // TCP, and TCP_* options
+    pub use crate::IPPROTO_UDP; // UDP
+    pub use crate::IPPROTO_UDPLITE; // UDP-Lite
+
+    // Socket-level options (level SOL_SOCKET)
+    pub use crate::SOL_SOCKET; // Level for the SO_* options
+    pub use crate::SO_ACCEPTCONN; // Whether the socket is listening (read-only)
+    pub use crate::SO_BINDTODEVICE; // Restrict to one netif, by name
+    pub use crate::SO_BROADCAST; // Permit sending to broadcast addresses
+    pub use crate::SO_CONTIMEO; // Connect timeout (not implemented by LwIP)
+    pub use crate::SO_ERROR; // Pending error, cleared on read
+    pub use crate::SO_KEEPALIVE; // TCP keepalive on/off
+    pub use crate::SO_LINGER; // Linger on close (struct linger)
+    pub use crate::SO_NO_CHECK; // Disable UDP checksums
+    pub use crate::SO_RCVBUF; // Receive buffer limit
+    pub use crate::SO_RCVTIMEO; // Receive timeout
+    pub use crate::SO_REUSEADDR; // Allow rebinding an address in use
+    pub use crate::SO_REUSEPORT; // Not implemented by LwIP
+    pub use crate::SO_SNDBUF; // Send buffer limit (not implemented by LwIP)
+    pub use crate::SO_SNDTIMEO; // Send timeout
+    pub use crate::SO_TYPE; // Socket type (read-only)
+
+    // IPv4 options (level IPPROTO_IP)
+    pub use crate::IP_ADD_MEMBERSHIP; // Join a multicast group (struct ip_mreq)
+    pub use crate::IP_DROP_MEMBERSHIP; // Leave a multicast group (struct ip_mreq)
+    pub use crate::IP_MULTICAST_IF; // Outgoing multicast interface
+    pub use crate::IP_MULTICAST_LOOP; // Loop back own multicast (u8)
+    pub use crate::IP_MULTICAST_TTL; // Multicast TTL (u8)
+    pub use crate::IP_PKTINFO; // Deliver IP_PKTINFO control messages
+    pub use crate::IP_TOS; // Type of service
+    pub use crate::IP_TTL; // Unicast TTL
+
+    // IPv6 options (level IPPROTO_IPV6)
+    pub use crate::IPV6_ADD_MEMBERSHIP; // Alias of IPV6_JOIN_GROUP
+    pub use crate::IPV6_CHECKSUM; // Checksum offset on raw sockets
+    pub use crate::IPV6_DROP_MEMBERSHIP; // Alias of IPV6_LEAVE_GROUP
+    pub use crate::IPV6_JOIN_GROUP; // Join a multicast group (struct ipv6_mreq)
+    pub use crate::IPV6_LEAVE_GROUP; // Leave a multicast group (struct ipv6_mreq)
+    pub use crate::IPV6_MULTICAST_LOOP; // Not implemented by LwIP; defined for std
+    pub use crate::IPV6_V6ONLY; // Refuse IPv4-mapped traffic
+
+    // TCP options (level IPPROTO_TCP)
+    pub use crate::TCP_KEEPALIVE; // Keepalive idle time in ms (LwIP extension)
+    pub use crate::TCP_KEEPCNT; // Keepalive probe count
+    pub use crate::TCP_KEEPIDLE; // Keepalive idle time in seconds
+    pub use crate::TCP_KEEPINTVL; // Keepalive probe interval in seconds
+    pub use crate::TCP_NODELAY; // Disable Nagle's algorithm
+
+    // send/recv flags and recvmsg msg_flags
+    pub use crate::MSG_CTRUNC; // Control data was truncated (msg_flags)
+    pub use crate::MSG_DONTWAIT; // Don't block for this call
+    pub use crate::MSG_MORE; // More data follows (delays the TCP push)
+    pub use crate::MSG_NOSIGNAL; // No SIGPIPE (always the case in LwIP)
+    pub use crate::MSG_OOB; // Out-of-band data (not supported)
+    pub use crate::MSG_PEEK; // Read without consuming
+    pub use crate::MSG_TRUNC; // Datagram was truncated (msg_flags)
+    pub use crate::MSG_WAITALL; // Wait for the full amount
+
+    // shutdown how
+    pub use crate::SHUT_RD; // Stop receiving
+    pub use crate::SHUT_RDWR; // Stop both
+    pub use crate::SHUT_WR; // Stop sending
+
+    // poll events
+    pub use crate::POLLERR; // Error (revents only)
+    pub use crate::POLLHUP; // Hang-up (revents only)
+    pub use crate::POLLIN; // Readable
+    pub use crate::POLLNVAL; // Not an open socket (revents only)
+    pub use crate::POLLOUT; // Writable
+
+    // fcntl/ioctl
+    pub use crate::FIONBIO; // ioctl: set non-blocking mode
+    pub use crate::FIONREAD; // ioctl: bytes queued for reading
+    pub use crate::F_GETFL; // fcntl: get file status flags
+    pub use crate::F_SETFL; // fcntl: set file status flags
+    pub use crate::O_NONBLOCK; // fcntl flag: non-blocking mode
+}
+
+// The errno (one of the E* constants above) recorded by the most recent failed LwIP call on this
+// task, so a caller that got -1 back can tell, say, ECONNREFUSED from EADDRINUSE. Only meaningful
+// immediately after the failing call: successful calls don't reset it, and any later failure
+// overwrites it. Read through std, so it follows whichever errno hook (errno / __errno) the
+// FreeRTOS port and LwIP's LWIP_ERRNO_INCLUDE settings provide.
+pub fn last_errno() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+// newlib's per-task errno slot, which LwIP and last_errno() both go through.
+extern "C" {
+    fn __errno() -> *mut c_int;
+}
+
+// Put back an errno that intervening calls may have overwritten before we return -1.
+fn set_errno(errno: i32) {
+    unsafe { *__errno() = errno };
+}
+
+// What went wrong, in the terms Rust networking code matches on. Mirrors the std::io::ErrorKind
+// variants the wrappers produce, so converting to std is one-to-one without tying the error type
+// to std::io.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    ConnectionRefused,
+    ConnectionReset,
+    ConnectionAborted,
+    NotConnected,
+    TimedOut,
+    AddrInUse,
+    AddrNotAvailable,
+    WouldBlock,
+    Interrupted,
+    BrokenPipe,
+    InvalidInput,
+    InvalidData,
+    NotFound,
+    OutOfMemory,
+    Unsupported,
+    WriteZero,
+    Other,
+}
+
+impl ErrorKind {
+    // The kind for an LwIP errno.
+    pub fn from_errno(errno: i32) -> ErrorKind {
+        match errno {
+            ECONNREFUSED => ErrorKind::ConnectionRefused,
+            ECONNRESET => ErrorKind::ConnectionReset,
+            ECONNABORTED => ErrorKind::ConnectionAborted,
+            ENOTCONN => ErrorKind::NotConnected,
+            ETIMEDOUT => ErrorKind::TimedOut,
+            EADDRINUSE => ErrorKind::AddrInUse,
+            EADDRNOTAVAIL => ErrorKind::AddrNotAvailable,
+            EAGAIN => ErrorKind::WouldBlock, // EWOULDBLOCK is the same value
+            EINTR => ErrorKind::Interrupted,
+            EPIPE => ErrorKind::BrokenPipe,
+            EINVAL => ErrorKind::InvalidInput,
+            ENOENT => ErrorKind::NotFound,
+            ENOMEM | ENOBUFS => ErrorKind::OutOfMemory,
+            EOPNOTSUPP | ENOPROTOOPT | EAFNOSUPPORT | ENOSYS => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ErrorKind> for std::io::ErrorKind {
+    fn from(kind: ErrorKind) -> std::io::ErrorKind {
+        use std::io::ErrorKind as Io;
+        match kind {
+            ErrorKind::ConnectionRefused => Io::ConnectionRefused,
+            ErrorKind::ConnectionReset => Io::ConnectionReset,
+            ErrorKind::ConnectionAborted => Io::ConnectionAborted,
+            ErrorKind::NotConnected => Io::NotConnected,
+            ErrorKind::TimedOut => Io::TimedOut,
+            ErrorKind::AddrInUse => Io::AddrInUse,
+            ErrorKind::AddrNotAvailable => Io::AddrNotAvailable,
+            ErrorKind::WouldBlock => Io::WouldBlock,
+            ErrorKind::Interrupted => Io::Interrupted,
+            ErrorKind::BrokenPipe => Io::BrokenPipe,
+            ErrorKind::InvalidInput => Io::InvalidInput,
+            ErrorKind::InvalidData => Io::InvalidData,
+            ErrorKind::NotFound => Io::NotFound,
+            ErrorKind::OutOfMemory => Io::OutOfMemory,
+            ErrorKind::Unsupported => Io::Unsupported,
+            ErrorKind::WriteZero => Io::WriteZero,
+            ErrorKind::Other => Io::Other,
+        }
+    }
+}
+
+// Error returned by the safe wrappers. Independent of std::io so the wrappers can serve code built
+// without std; with the `std` feature it converts into std::io::Error, so `?` still works in
+// functions returning io::Result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    // A failed LwIP call: the errno it left (one of the E* constants) and what that means.
+    Errno {
+        errno: i32,
+        kind: ErrorKind,
+    },
+    // A failed getaddrinfo: one of the EAI_* codes.
+    Resolve {
+        code: i32,
+        kind: ErrorKind,
+    },
+    // Rejected by the wrapper before LwIP was called, or a result it couldn't make sense of.
+    Invalid {
+        kind: ErrorKind,
+        message: &'static str,
+    },
+}
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+impl Error {
+    pub fn from_errno(errno: i32) -> Error {
+        Error::Errno {
+            errno,
+            kind: ErrorKind::from_errno(errno),
+        }
+    }
+
+    // The errno of the most recent failed LwIP call on this task (see last_errno).
+    pub fn last() -> Error {
+        Error::from_errno(last_errno())
+    }
+
+    pub fn new(kind: ErrorKind, message: &'static str) -> Error {
+        Error::Invalid { kind, message }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            Error::Errno { kind, .. }
+            | Error::Resolve { kind, .. }
+            | Error::Invalid { kind, .. } => kind,
+        }
+    }
+
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            Error::Errno { errno, .. } => Some(errno),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Error::Errno { errno, .. } => LwipErrno(errno).fmt(f),
+            Error::Resolve { code, .. } => LwipEaiError(code).fmt(f),
+            Error::Invalid { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(e: Error) -> std::io::Error {
+        match e {
+            Error::Errno { errno, .. } => errno_to_io_error(errno),
+            Error::Resolve { code, kind } => std::io::Error::new(kind.into(), LwipEaiError(code)),
+            Error::Invalid { kind, message } => std::io::Error::new(kind.into(), message),
+        }
+    }
+}
+
+// Map an LwIP errno to the std::io::ErrorKind that Rust networking code matches on.
+#[cfg(feature = "std")]
+pub fn errno_to_error_kind(errno: i32) -> std::io::ErrorKind {
+    ErrorKind::from_errno(errno).into()
+}
+
+// Error payload for an LwIP errno the platform's std can't represent with the right ErrorKind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LwipErrno(pub i32);
+
+impl core::fmt::Display for LwipErrno {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "LwIP errno {}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LwipErrno {}
+
+// Build an io::Error for an LwIP errno with the kind from errno_to_error_kind. std can only attach
+// a raw_os_error by deriving the kind itself, so the raw code is kept whenever the port's std
+// agrees with our mapping; otherwise the errno travels as an LwipErrno payload (get_ref()).
+#[cfg(feature = "std")]
+pub fn errno_to_io_error(errno: i32) -> std::io::Error {
+    let kind = errno_to_error_kind(errno);
+    let os_error = std::io::Error::from_raw_os_error(errno);
+    if os_error.kind() == kind {
+        os_error
+    } else {
+        std::io::Error::new(kind, LwipErrno(errno))
+    }
+}
+
 pub fn socket(family: c_int, socket_type: c_int, protocol: c_int) -> c_int {
     let socket_handle = unsafe { lwip_socket(family, socket_type, protocol) };
     socket_handle
 }
 
+// socket() for callers that want `?`: the new descriptor, or the LwIP errno as an Error.
+pub fn socket_checked(family: c_int, socket_type: c_int, protocol: c_int) -> Result<RawSocket> {
+    match socket(family, socket_type, protocol) {
+        -1 => Err(Error::last()),
+        sock => Ok(sock),
+    }
+}
+
 pub fn setsockopt(
     sock: RawSocket,
     level: c_int,
@@ -178,6 +582,336 @@ pub fn getsockopt(
     }
 }
 
+// Integer-valued options without the pointer/length plumbing. Unlike the raw wrappers above,
+// failures carry the errno LwIP left behind.
+pub fn get_sockopt_int(sock: RawSocket, level: c_int, optname: c_int) -> Result<i32> {
+    let mut value: c_int = 0;
+    let mut len = core::mem::size_of::<c_int>() as socklen_t;
+    let retval = getsockopt(
+        sock,
+        level,
+        optname,
+        &mut value as *mut c_int as *mut c_void,
+        &mut len,
+    );
+    match retval {
+        0 => Ok(value),
+        _ => Err(Error::last()),
+    }
+}
+
+pub fn set_sockopt_int(sock: RawSocket, level: c_int, optname: c_int, value: i32) -> Result<()> {
+    let retval = setsockopt(
+        sock,
+        level,
+        optname,
+        &value as *const c_int as *const c_void,
+        core::mem::size_of::<c_int>() as socklen_t,
+    );
+    match retval {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+// Pending error on the socket (SO_ERROR), clearing it. This is how the outcome of a non-blocking
+// connect is collected once poll reports the socket writable: Ok(None) means it connected.
+pub fn take_error(sock: RawSocket) -> Result<Option<Error>> {
+    match get_sockopt_int(sock, SOL_SOCKET, SO_ERROR)? {
+        0 => Ok(None),
+        errno => Ok(Some(Error::from_errno(errno))),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectState {
+    Connected,
+    InProgress,
+}
+
+// Start a connect on a socket already put in non-blocking mode (set_nonblocking). On InProgress,
+// wait for the socket to become writable and then collect the outcome with connect_result:
+//
+//     if connect_nonblocking(sock, addr)? == ConnectState::InProgress {
+//         let mut fds = [pollfd { fd: sock, events: POLLOUT, revents: 0 }];
+//         poll_fds(&mut fds, Some(Duration::from_secs(5)))?;
+//         connect_result(sock)?;
+//     }
+//
+// POLLOUT alone doesn't mean success - a refused or timed-out connect is also reported writable
+// (with POLLERR), which is why the SO_ERROR check is needed.
+pub fn connect_nonblocking(sock: RawSocket, addr: SocketAddr) -> Result<ConnectState> {
+    let (name, namelen) = socket_addr_to_sockaddr(&addr)?;
+    let retval = connect(
+        sock,
+        &name as *const sockaddr_storage as *const sockaddr,
+        namelen,
+    );
+    if retval == 0 {
+        return Ok(ConnectState::Connected);
+    }
+    match last_errno() {
+        EINPROGRESS | EALREADY => Ok(ConnectState::InProgress),
+        EISCONN => Ok(ConnectState::Connected),
+        errno => Err(Error::from_errno(errno)),
+    }
+}
+
+// Outcome of a non-blocking connect once poll has reported the socket writable: Ok if it
+// connected, otherwise the error it failed with.
+pub fn connect_result(sock: RawSocket) -> Result<()> {
+    match take_error(sock)? {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+// Disable (true) or re-enable Nagle's algorithm. The flag lives on the connection's pcb and is not
+// inherited by sockets returned from accept - LwIP only copies SO_REUSEADDR and SO_KEEPALIVE
+// across - so servers have to set it on each accepted connection, not on the listener.
+pub fn set_nodelay(sock: RawSocket, nodelay: bool) -> Result<()> {
+    set_sockopt_int(sock, IPPROTO_TCP, TCP_NODELAY, nodelay as i32)
+}
+
+pub fn nodelay(sock: RawSocket) -> Result<bool> {
+    get_sockopt_int(sock, IPPROTO_TCP, TCP_NODELAY).map(|v| v != 0)
+}
+
+// Allow bind to reuse a local address still held by a connection in TIME_WAIT, so a service can
+// restart without EADDRINUSE. Must be set before bind. LwIP only implements this when built with
+// SO_REUSE; otherwise the call fails with ENOPROTOOPT rather than being silently ignored.
+pub fn set_reuse_address(sock: RawSocket, reuse: bool) -> Result<()> {
+    set_sockopt_int(sock, SOL_SOCKET, SO_REUSEADDR, reuse as i32)
+}
+
+pub fn reuse_address(sock: RawSocket) -> Result<bool> {
+    get_sockopt_int(sock, SOL_SOCKET, SO_REUSEADDR).map(|v| v != 0)
+}
+
+// Restrict an AF_INET6 socket to IPv6 (true) or let a socket bound to :: also carry IPv4 traffic
+// as IPv4-mapped addresses (false, LwIP's default on a dual-stack build). LwIP looks at the flag
+// when the socket is bound, so set it before bind. On a build without IPv6 - which these bindings
+// come from - this fails with EOPNOTSUPP instead of appearing to succeed.
+#[cfg(feature = "ipv6")]
+pub fn set_only_v6(sock: RawSocket, only: bool) -> Result<()> {
+    if !IPV6_ENABLED {
+        return Err(Error::from_errno(EOPNOTSUPP));
+    }
+    set_sockopt_int(sock, IPPROTO_IPV6, IPV6_V6ONLY, only as i32)
+}
+
+#[cfg(feature = "ipv6")]
+pub fn only_v6(sock: RawSocket) -> Result<bool> {
+    if !IPV6_ENABLED {
+        return Err(Error::from_errno(EOPNOTSUPP));
+    }
+    get_sockopt_int(sock, IPPROTO_IPV6, IPV6_V6ONLY).map(|v| v != 0)
+}
+
+// None: close() returns at once and LwIP sends what is queued in the background (the default).
+// Some(t): close() blocks until queued data is sent or t elapses. Some(Duration::ZERO): close()
+// resets the connection, discarding anything unsent. LwIP takes whole seconds, so t is rounded up.
+// Needs LwIP built with LWIP_SO_LINGER; otherwise LwIP's ENOPROTOOPT is returned.
+pub fn set_linger(sock: RawSocket, linger: Option<Duration>) -> Result<()> {
+    let value = match linger {
+        None => linger {
+            l_onoff: 0,
+            l_linger: 0,
+        },
+        Some(t) => linger {
+            l_onoff: 1,
+            l_linger: (t.as_secs() + (t.subsec_nanos() > 0) as u64).min(c_int::MAX as u64) as c_int,
+        },
+    };
+    let retval = setsockopt(
+        sock,
+        SOL_SOCKET,
+        SO_LINGER,
+        &value as *const linger as *const c_void,
+        core::mem::size_of::<linger>() as socklen_t,
+    );
+    match retval {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+pub fn linger(sock: RawSocket) -> Result<Option<Duration>> {
+    let mut value = linger {
+        l_onoff: 0,
+        l_linger: 0,
+    };
+    let mut len = core::mem::size_of::<linger>() as socklen_t;
+    let retval = getsockopt(
+        sock,
+        SOL_SOCKET,
+        SO_LINGER,
+        &mut value as *mut linger as *mut c_void,
+        &mut len,
+    );
+    match retval {
+        0 if value.l_onoff == 0 => Ok(None),
+        0 => Ok(Some(Duration::from_secs(value.l_linger.max(0) as u64))),
+        _ => Err(Error::last()),
+    }
+}
+
+// Time-to-live for unicast IPv4 packets sent from this socket. LwIP keeps it in a u8_t, so values
+// above 255 are rejected with EINVAL rather than truncated.
+pub fn set_ttl(sock: RawSocket, ttl: u32) -> Result<()> {
+    if ttl > u8::MAX as u32 {
+        return Err(Error::from_errno(EINVAL));
+    }
+    set_sockopt_int(sock, IPPROTO_IP, IP_TTL, ttl as i32)
+}
+
+// There is no IPv6 hop-limit counterpart: LwIP has no IPV6_UNICAST_HOPS option (packets use the
+// netif's hop limit), so it could only ever fail with EOPNOTSUPP.
+pub fn ttl(sock: RawSocket) -> Result<u32> {
+    get_sockopt_int(sock, IPPROTO_IP, IP_TTL).map(|v| v.max(0) as u32)
+}
+
+// Permit sending to broadcast addresses (255.255.255.255 or a subnet's directed broadcast). LwIP
+// only enforces, and only accepts, SO_BROADCAST when built with IP_SOF_BROADCAST; without it the
+// call fails with ENOPROTOOPT and broadcasts are allowed regardless.
+pub fn set_broadcast(sock: RawSocket, on: bool) -> Result<()> {
+    set_sockopt_int(sock, SOL_SOCKET, SO_BROADCAST, on as i32)
+}
+
+pub fn broadcast(sock: RawSocket) -> Result<bool> {
+    get_sockopt_int(sock, SOL_SOCKET, SO_BROADCAST).map(|v| v != 0)
+}
+
+// Receive buffer limit (SO_RCVBUF), needing LwIP built with LWIP_SO_RCVBUF. LwIP stores the value
+// as given rather than clamping it, and the getter reports that stored value; bytes in flight on a
+// TCP connection are still bounded by the compiled-in TCP_WND.
+pub fn set_recv_buffer_size(sock: RawSocket, bytes: usize) -> Result<()> {
+    set_sockopt_int(
+        sock,
+        SOL_SOCKET,
+        SO_RCVBUF,
+        bytes.min(i32::MAX as usize) as i32,
+    )
+}
+
+pub fn recv_buffer_size(sock: RawSocket) -> Result<usize> {
+    get_sockopt_int(sock, SOL_SOCKET, SO_RCVBUF).map(|v| v.max(0) as usize)
+}
+
+// LwIP has no per-socket send buffer (SO_SNDBUF): queued TCP data is bounded by the compiled-in
+// TCP_SND_BUF. These report LwIP's ENOPROTOOPT rather than pretending the option was applied,
+// so callers can tell it had no effect.
+pub fn set_send_buffer_size(sock: RawSocket, bytes: usize) -> Result<()> {
+    set_sockopt_int(
+        sock,
+        SOL_SOCKET,
+        SO_SNDBUF,
+        bytes.min(i32::MAX as usize) as i32,
+    )
+}
+
+pub fn send_buffer_size(sock: RawSocket) -> Result<usize> {
+    get_sockopt_int(sock, SOL_SOCKET, SO_SNDBUF).map(|v| v.max(0) as usize)
+}
+
+// Whether SO_RCVTIMEO/SO_SNDTIMEO take a plain int of milliseconds
+// (LWIP_SO_SNDRCVTIMEO_NONSTANDARD) rather than a struct timeval. Found on first use by reading
+// the option with an int-sized buffer, which the timeval build rejects as too short.
+static SOCKOPT_TIMEO_IS_INT: OnceLock<bool> = OnceLock::new();
+
+fn sockopt_timeo_is_int(sock: RawSocket) -> bool {
+    if let Some(&is_int) = SOCKOPT_TIMEO_IS_INT.get() {
+        return is_int;
+    }
+    // Only a definite answer is cached: a bad descriptor says nothing about the build.
+    let is_int = match get_sockopt_int(sock, SOL_SOCKET, SO_RCVTIMEO) {
+        Ok(_) => true,
+        Err(e) if e.errno() == Some(EINVAL) => false,
+        Err(_) => return false,
+    };
+    *SOCKOPT_TIMEO_IS_INT.get_or_init(|| is_int)
+}
+
+// Bound how long a blocking recv (read timeout) or send (write timeout) waits; None waits forever.
+// A zero duration is rejected, since LwIP reads 0 as "no timeout". Requires LwIP built with
+// LWIP_SO_RCVTIMEO / LWIP_SO_SNDTIMEO.
+pub fn set_read_timeout(sock: RawSocket, timeout: Option<Duration>) -> Result<()> {
+    set_timeout(sock, SO_RCVTIMEO, timeout)
+}
+
+pub fn set_write_timeout(sock: RawSocket, timeout: Option<Duration>) -> Result<()> {
+    set_timeout(sock, SO_SNDTIMEO, timeout)
+}
+
+pub fn read_timeout(sock: RawSocket) -> Result<Option<Duration>> {
+    timeout(sock, SO_RCVTIMEO)
+}
+
+pub fn write_timeout(sock: RawSocket) -> Result<Option<Duration>> {
+    timeout(sock, SO_SNDTIMEO)
+}
+
+fn set_timeout(sock: RawSocket, optname: c_int, timeout: Option<Duration>) -> Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot set a zero duration timeout",
+        ));
+    }
+    let timeout = timeout.unwrap_or(Duration::ZERO);
+    if sockopt_timeo_is_int(sock) {
+        // Round up so a sub-millisecond timeout doesn't become "forever".
+        let ms = timeout
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(c_int::MAX as u128) as i32;
+        return set_sockopt_int(sock, SOL_SOCKET, optname, ms);
+    }
+    let us = timeout.as_nanos().div_ceil(1_000);
+    let mut tv: timeval = unsafe { core::mem::zeroed() };
+    // tv_sec is the port's (signed) time_t, which may be only 32 bits: clamp to its maximum
+    // rather than let a long timeout wrap negative.
+    let tv_sec_max = (1u128 << (8 * core::mem::size_of_val(&tv.tv_sec) - 1)) - 1;
+    tv.tv_sec = (us / 1_000_000).min(tv_sec_max) as _;
+    tv.tv_usec = (us % 1_000_000) as _;
+    let retval = setsockopt(
+        sock,
+        SOL_SOCKET,
+        optname,
+        &tv as *const timeval as *const c_void,
+        core::mem::size_of::<timeval>() as socklen_t,
+    );
+    match retval {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+fn timeout(sock: RawSocket, optname: c_int) -> Result<Option<Duration>> {
+    let timeout = if sockopt_timeo_is_int(sock) {
+        Duration::from_millis(get_sockopt_int(sock, SOL_SOCKET, optname)?.max(0) as u64)
+    } else {
+        let mut tv: timeval = unsafe { core::mem::zeroed() };
+        let mut len = core::mem::size_of::<timeval>() as socklen_t;
+        let retval = getsockopt(
+            sock,
+            SOL_SOCKET,
+            optname,
+            &mut tv as *mut timeval as *mut c_void,
+            &mut len,
+        );
+        if retval != 0 {
+            return Err(Error::last());
+        }
+        Duration::from_secs(tv.tv_sec.max(0) as u64)
+            + Duration::from_micros(tv.tv_usec.max(0) as u64)
+    };
+    Ok(match timeout {
+        Duration::ZERO => None,
+        t => Some(t),
+    })
+}
+
 pub fn bind(sock: RawSocket, name: *const sockaddr, namelen: socklen_t) -> c_int {
     let retval = unsafe { lwip_bind(sock, name, namelen) };
     match retval {
@@ -187,13 +921,53 @@ pub fn bind(sock: RawSocket, name: *const sockaddr, namelen: socklen_t) -> c_int
 }
 
 pub fn connect(sock: RawSocket, name: *const sockaddr, namelen: socklen_t) -> c_int {
-    let retval = unsafe { lwip_connect(sock, name, namelen) };
+    let retval = with_priority_boost(sock, || unsafe { lwip_connect(sock, name, namelen) });
+    if CONNECTION_LOGGER_ACTIVE.load(Ordering::Relaxed) {
+        // The SO_TYPE query and the logger itself may overwrite errno (LwIP 2.0 even zeroes it
+        // on success), so keep the connect's own errno for the caller.
+        let err = if retval == 0 { 0 } else { last_errno() };
+        log_connect(sock, name, namelen, err);
+        if retval != 0 {
+            set_errno(err);
+        }
+    }
     match retval {
         0 => 0,
         _ => -1,
     }
 }
 
+// Release a descriptor obtained from socket() or accept(). Every descriptor must be closed
+// exactly once: LwIP has only MEMP_NUM_NETCONN of them, and leaked ones are never reclaimed.
+pub fn close(sock: RawSocket) -> c_int {
+    if PRIORITY_BOOST_ACTIVE.load(Ordering::Relaxed) {
+        priority_boost(sock, 0);
+    }
+    if RECV_WATERMARKS_ACTIVE.load(Ordering::Relaxed) {
+        clear_recv_watermark(sock);
+    }
+    let closed_event = if CONNECTION_LOGGER_ACTIVE.load(Ordering::Relaxed)
+        && getsockopt_int(sock, SOL_SOCKET, SO_TYPE) == Some(SOCK_STREAM)
+    {
+        Some(ConnectionEvent::Closed {
+            peer: sockaddr_of(sock, getpeername),
+            graceful: !lingers_abortively(sock),
+        })
+    } else {
+        None
+    };
+    let retval = unsafe { lwip_close(sock) };
+    match retval {
+        0 => {
+            if let Some(event) = closed_event {
+                emit_connection_event(event);
+            }
+            0
+        }
+        _ => -1,
+    }
+}
+
 pub fn listen(sock: RawSocket, backlog: c_int) -> c_int {
     let retval = unsafe { lwip_listen(sock, backlog) };
     match retval {
@@ -202,12 +976,19 @@ pub fn listen(sock: RawSocket, backlog: c_int) -> c_int {
     }
 }
 
+// Returns the descriptor of the accepted connection, or -1.
 pub fn accept(sock: RawSocket, name: *mut sockaddr, namelen: *mut socklen_t) -> c_int {
-    let retval = unsafe { lwip_accept(sock, name, namelen) };
-    match retval {
-        0 => 0,
-        _ => -1,
+    let retval = with_priority_boost(sock, || unsafe { lwip_accept(sock, name, namelen) });
+    if retval < 0 {
+        return -1;
     }
+    if CONNECTION_LOGGER_ACTIVE.load(Ordering::Relaxed) {
+        emit_connection_event(ConnectionEvent::Accepted {
+            local: sockaddr_of(retval, getsockname),
+            peer: sockaddr_of(retval, getpeername),
+        });
+    }
+    retval
 }
 
 pub fn getsockname(sock: RawSocket, name: *mut sockaddr, namelen: *mut socklen_t) -> c_int {
@@ -218,7 +999,70 @@ pub fn getsockname(sock: RawSocket, name: *mut sockaddr, namelen: *mut socklen_t
 }
 
 pub fn send(sock: RawSocket, mem: *const c_void, len: i32, flags: c_int) -> i32 {
-    unsafe { lwip_send(sock, mem, len, flags) }
+    with_priority_boost(sock, || unsafe { lwip_send(sock, mem, len, flags) })
+}
+
+// Safe send(): returns how many bytes LwIP accepted, which may be fewer than `buf.len()` - the
+// caller loops for the rest - or the LwIP errno as an Error. Buffers over i32::MAX bytes are
+// sent partially rather than having their length wrap.
+pub fn send_slice(sock: RawSocket, buf: &[u8], flags: c_int) -> Result<usize> {
+    let len = buf.len().min(i32::MAX as usize) as i32;
+    match send(sock, buf.as_ptr() as *const c_void, len, flags) {
+        n if n < 0 => Err(Error::last()),
+        n => Ok(n as usize),
+    }
+}
+
+// recv_slice/send_slice, retrying while the call is interrupted (EINTR) as std sockets do. Only
+// interruptions are retried: a short read or write that did transfer data returns normally.
+pub fn recv_slice_retry(sock: RawSocket, buf: &mut [u8], flags: c_int) -> Result<usize> {
+    loop {
+        match recv_slice(sock, buf, flags) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+pub fn send_slice_retry(sock: RawSocket, buf: &[u8], flags: c_int) -> Result<usize> {
+    loop {
+        match send_slice(sock, buf, flags) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+// Send the whole of `buf`, looping over partial sends like Write::write_all. On a non-blocking
+// socket a full send buffer ends the loop with WouldBlock instead of spinning; as with write_all,
+// part of `buf` may already have gone by then, so such callers are usually better off tracking
+// progress with send_slice themselves.
+pub fn send_all(sock: RawSocket, mut buf: &[u8], flags: c_int) -> Result<()> {
+    while !buf.is_empty() {
+        match send_slice_retry(sock, buf, flags)? {
+            0 => {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            n => buf = &buf[n..],
+        }
+    }
+    Ok(())
+}
+
+// recv_slice/send_slice for code that is generic over std::io::Result-returning operations: the
+// same calls, with the Error converted to io::Error, keeping its kind and errno (see
+// errno_to_io_error).
+#[cfg(feature = "std")]
+pub fn recv_io(sock: RawSocket, buf: &mut [u8], flags: c_int) -> std::io::Result<usize> {
+    recv_slice(sock, buf, flags).map_err(Into::into)
+}
+
+#[cfg(feature = "std")]
+pub fn send_io(sock: RawSocket, buf: &[u8], flags: c_int) -> std::io::Result<usize> {
+    send_slice(sock, buf, flags).map_err(Into::into)
 }
 
 pub fn sendto(
@@ -231,15 +1075,69 @@ pub fn sendto(
 ) -> i32 {
     // Call lwip_sendto regardless of socket type. It will return an error for invalid combinations.
     // Previously only SOCK_DGRAM was supported, but we also need raw socket support.
-    unsafe { lwip_sendto(sock, mem, len, flags, to, tolen) }
+    with_priority_boost(sock, || unsafe {
+        lwip_sendto(sock, mem, len, flags, to, tolen)
+    })
 }
 
 pub fn sendmsg(sock: RawSocket, message: *const msghdr, flags: c_int) -> i32 {
-    unsafe { lwip_sendmsg(sock, message, flags) }
+    with_priority_boost(sock, || unsafe { lwip_sendmsg(sock, message, flags) })
+}
+
+// Gather-write `bufs` in one sendmsg call, e.g. a header and payload held separately, returning
+// the bytes sent. At most IOV_MAX buffers.
+pub fn send_vectored(sock: RawSocket, bufs: &[IoSlice], flags: c_int) -> Result<usize> {
+    if bufs.len() > IOV_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "more than IOV_MAX buffers",
+        ));
+    }
+    let mut iov: Vec<iovec> = bufs
+        .iter()
+        .map(|buf| iovec {
+            iov_base: buf.as_ptr() as *mut c_void,
+            iov_len: buf.len().min(i32::MAX as usize) as size_t,
+        })
+        .collect();
+    let message = msghdr {
+        msg_name: core::ptr::null_mut(),
+        msg_namelen: 0,
+        msg_iov: iov.as_mut_ptr(),
+        msg_iovlen: iov.len() as c_int,
+        msg_control: core::ptr::null_mut(),
+        msg_controllen: 0,
+        msg_flags: 0,
+    };
+    match sendmsg(sock, &message, flags) {
+        n if n < 0 => Err(Error::last()),
+        n => Ok(n as usize),
+    }
 }
 
 pub fn recv(sock: RawSocket, mem: *mut c_void, len: i32, flags: c_int) -> i32 {
-    unsafe { lwip_recv(sock, mem, len as size_t, flags) }
+    with_priority_boost(sock, || unsafe {
+        lwip_recv(sock, mem, len as size_t, flags)
+    })
+}
+
+// Safe recv(): reads into `buf`, returning the byte count, Ok(0) once the peer has closed the
+// connection, or the LwIP errno as an Error. Buffers over i32::MAX bytes are read into
+// partially rather than having their length wrap.
+// A recv that hits SO_RCVTIMEO (set_read_timeout) fails with EWOULDBLOCK, i.e. WouldBlock, as on
+// Unix.
+pub fn recv_slice(sock: RawSocket, buf: &mut [u8], flags: c_int) -> Result<usize> {
+    let len = buf.len().min(i32::MAX as usize) as i32;
+    match recv(sock, buf.as_mut_ptr() as *mut c_void, len, flags) {
+        n if n < 0 => Err(Error::last()),
+        n => Ok(n as usize),
+    }
+}
+
+// Read the head of the receive queue without consuming it: the same bytes are returned again by
+// the next peek or recv.
+pub fn peek(sock: RawSocket, buf: &mut [u8]) -> Result<usize> {
+    recv_slice(sock, buf, MSG_PEEK)
 }
 
 pub fn recvfrom(
@@ -252,11 +1150,54 @@ pub fn recvfrom(
 ) -> i32 {
     // Call lwip_recvfrom regardless of socket type. It will return an error for invalid combinations.
     // Previously only SOCK_DGRAM was supported, but we also need raw socket support.
-    unsafe { lwip_recvfrom(sock, mem, len as size_t, flags, from, fromlen) }
+    with_priority_boost(sock, || unsafe {
+        lwip_recvfrom(sock, mem, len as size_t, flags, from, fromlen)
+    })
 }
 
 pub fn recvmsg(sock: RawSocket, message: *mut msghdr, flags: c_int) -> i32 {
-    unsafe { lwip_recvmsg(sock, message, flags) }
+    with_priority_boost(sock, || unsafe { lwip_recvmsg(sock, message, flags) })
+}
+
+// Scatter-read into `bufs`, filling them in order, and return the total bytes received; Ok(0)
+// once the peer has closed a stream socket. At most IOV_MAX buffers.
+pub fn recv_vectored(sock: RawSocket, bufs: &mut [IoSliceMut], flags: c_int) -> Result<usize> {
+    recv_vectored_with_flags(sock, bufs, flags).map(|(n, _)| n)
+}
+
+// As recv_vectored, also returning the msg_flags LwIP set - on a datagram socket
+// RecvMsgFlags::truncated() means the datagram was longer than `bufs` and the excess was dropped.
+pub fn recv_vectored_with_flags(
+    sock: RawSocket,
+    bufs: &mut [IoSliceMut],
+    flags: c_int,
+) -> Result<(usize, RecvMsgFlags)> {
+    if bufs.len() > IOV_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "more than IOV_MAX buffers",
+        ));
+    }
+    let mut iov: Vec<iovec> = bufs
+        .iter_mut()
+        .map(|buf| iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len().min(i32::MAX as usize) as size_t,
+        })
+        .collect();
+    let mut message = msghdr {
+        msg_name: core::ptr::null_mut(),
+        msg_namelen: 0,
+        msg_iov: iov.as_mut_ptr(),
+        msg_iovlen: iov.len() as c_int,
+        msg_control: core::ptr::null_mut(),
+        msg_controllen: 0,
+        msg_flags: 0,
+    };
+    match recvmsg(sock, &mut message, flags) {
+        n if n < 0 => Err(Error::last()),
+        n => Ok((n as usize, RecvMsgFlags(message.msg_flags))),
+    }
 }
 
 pub fn getpeername(sock: RawSocket, name: *mut sockaddr, namelen: *mut socklen_t) -> c_int {
@@ -276,24 +1217,3410 @@ pub fn freeaddrinfo(ai: *mut addrinfo) {
     unsafe { lwip_freeaddrinfo(ai) };
 }
 
-pub fn is_netif_initialised() -> bool {
-    // Crude check that the interface is up by seeing if an IP address has been assigned.
-    // Unfortunately, LwIP does not provide a clean API function to do this.
-    unsafe { gnetif.ip_addr.addr != 0 }
+// getaddrinfo failure codes from LwIP's netdb.h (not part of the generated bindings).
+pub const EAI_NONAME: c_int = 200;
+pub const EAI_SERVICE: c_int = 201;
+pub const EAI_FAIL: c_int = 202;
+pub const EAI_MEMORY: c_int = 203;
+pub const EAI_FAMILY: c_int = 204;
+
+// Error payload carrying the EAI_* code from a failed getaddrinfo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LwipEaiError(pub i32);
+
+impl core::fmt::Display for LwipEaiError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let reason = match self.0 {
+            EAI_NONAME => "name does not resolve",
+            EAI_SERVICE => "service not supported",
+            EAI_FAIL => "non-recoverable failure in name resolution",
+            EAI_MEMORY => "memory allocation failure",
+            EAI_FAMILY => "address family not supported",
+            _ => "unknown error",
+        };
+        write!(f, "getaddrinfo: {} (EAI {})", reason, self.0)
+    }
 }
 
-pub fn shutdown(sock: RawSocket, how: c_int) -> i32 {
-    unsafe { lwip_shutdown(sock, how) }
+#[cfg(feature = "std")]
+impl std::error::Error for LwipEaiError {}
+
+fn eai_error(code: c_int) -> Error {
+    let kind = match code {
+        EAI_NONAME => ErrorKind::NotFound,
+        EAI_SERVICE => ErrorKind::InvalidInput,
+        EAI_MEMORY => ErrorKind::OutOfMemory,
+        EAI_FAMILY => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    };
+    Error::Resolve { code, kind }
 }
 
-pub fn poll(fds: *const pollfd, nfds: nfds_t, timeout: core::ffi::c_int) -> i32 {
-    unsafe { lwip_poll(fds, nfds, timeout) }
+// Resolve `host` (and optionally a numeric `service`, which LwIP uses as the port) to socket
+// addresses, freeing LwIP's addrinfo list before returning. Entries with a family this crate can't
+// decode are skipped.
+pub fn resolve(
+    host: &str,
+    service: Option<&str>,
+    hints: Option<&addrinfo>,
+) -> Result<Vec<SocketAddr>> {
+    let invalid = |_| Error::new(ErrorKind::InvalidInput, "name contains a NUL byte");
+    let host = CString::new(host).map_err(invalid)?;
+    let service = service.map(CString::new).transpose().map_err(invalid)?;
+
+    let mut res: *mut addrinfo = core::ptr::null_mut();
+    let retval = getaddrinfo(
+        host.as_ptr(),
+        service.as_ref().map_or(core::ptr::null(), |s| s.as_ptr()),
+        hints.map_or(core::ptr::null(), |h| h as *const addrinfo),
+        &mut res,
+    );
+    if retval != 0 {
+        if !res.is_null() {
+            freeaddrinfo(res);
+        }
+        return Err(eai_error(retval));
+    }
+
+    let mut addrs = Vec::new();
+    let mut ai = res;
+    while !ai.is_null() {
+        let entry = unsafe { &*ai };
+        let len = (entry.ai_addrlen as usize).min(core::mem::size_of::<sockaddr_storage>());
+        if !entry.ai_addr.is_null() {
+            let mut storage: sockaddr_storage = unsafe { core::mem::zeroed() };
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    entry.ai_addr as *const u8,
+                    &mut storage as *mut sockaddr_storage as *mut u8,
+                    len,
+                );
+            }
+            if let Some(addr) = sockaddr_to_socket_addr(&storage, len as socklen_t) {
+                addrs.push(addr);
+            }
+        }
+        ai = entry.ai_next;
+    }
+    freeaddrinfo(res);
+    Ok(addrs)
 }
 
-pub fn fcntl(s: core::ffi::c_int, cmd: core::ffi::c_int, val: core::ffi::c_int) -> i32 {
-    unsafe { lwip_fcntl(s, cmd, val) }
+// Resolve a hostname (or address literal) to socket addresses on `port`. LwIP's getaddrinfo
+// returns a single result: with both stacks enabled it is the IPv4 address if the name has one,
+// otherwise IPv6 (LWIP_DNS_ADDRTYPE_DEFAULT). On a build without IPv6 an IPv6 literal or an
+// IPv6-only name yields an empty list rather than an error.
+pub fn lookup_host(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    if !IPV6_ENABLED && host.parse::<std::net::Ipv6Addr>().is_ok() {
+        return Ok(Vec::new());
+    }
+    let mut hints: addrinfo = unsafe { core::mem::zeroed() };
+    hints.ai_family = AF_UNSPEC;
+    let mut addrs = match resolve(host, None, Some(&hints)) {
+        Ok(addrs) => addrs,
+        Err(Error::Resolve {
+            code: EAI_FAMILY, ..
+        }) if !IPV6_ENABLED => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    for addr in &mut addrs {
+        addr.set_port(port);
+    }
+    Ok(addrs)
 }
 
-pub fn ioctl(s: core::ffi::c_int, cmd: core::ffi::c_long, argp: *mut core::ffi::c_void) -> i32 {
-    unsafe { lwip_ioctl(s, cmd, argp) }
+// Anything TcpStream::connect can turn into candidate addresses: a SocketAddr, a (host, port)
+// pair, or a "host:port" string (IPv6 literals in brackets, as for std).
+pub trait ToLwipAddrs {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>>;
+}
+
+impl ToLwipAddrs for SocketAddr {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(vec![*self])
+    }
+}
+
+impl ToLwipAddrs for SocketAddrV4 {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::V4(*self)])
+    }
+}
+
+impl ToLwipAddrs for (Ipv4Addr, u16) {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::from(*self)])
+    }
+}
+
+impl ToLwipAddrs for (&str, u16) {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>> {
+        lookup_host(self.0, self.1)
+    }
+}
+
+impl ToLwipAddrs for str {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>> {
+        if let Ok(addr) = self.parse::<SocketAddr>() {
+            return Ok(vec![addr]);
+        }
+        let invalid = || Error::new(ErrorKind::InvalidInput, "expected host:port");
+        let (host, port) = self.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse::<u16>().map_err(|_| invalid())?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        lookup_host(host, port)
+    }
+}
+
+impl ToLwipAddrs for String {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>> {
+        self.as_str().to_lwip_addrs()
+    }
+}
+
+impl<T: ToLwipAddrs + ?Sized> ToLwipAddrs for &T {
+    fn to_lwip_addrs(&self) -> Result<Vec<SocketAddr>> {
+        (**self).to_lwip_addrs()
+    }
+}
+
+// Handle to one of LwIP's network interfaces. netif structures are statically allocated by the
+// port and live for the life of the program, so the handle is just the pointer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Netif(*mut netif);
+
+// Look up an interface by its LwIP name: the two-character interface name followed by its number,
+// e.g. "st0" or "wl1". None if no such interface has been added.
+pub fn netif_find(name: &str) -> Option<Netif> {
+    let name = CString::new(name).ok()?;
+    let ptr = unsafe { lwip_netif_find(name.as_ptr()) };
+    if ptr.is_null() {
+        None
+    } else {
+        Some(Netif(ptr))
+    }
+}
+
+impl Netif {
+    // The interface the port brings up as gnetif; what the netif_* free functions query.
+    // Older toolchains still want unsafe around addr_of! on an extern static.
+    #[allow(unused_unsafe)]
+    pub fn default_interface() -> Netif {
+        Netif(unsafe { core::ptr::addr_of!(gnetif) as *mut netif })
+    }
+
+    pub fn as_ptr(&self) -> *mut netif {
+        self.0
+    }
+
+    // Run `f` on a consistent copy of the interface's struct netif. LwIP changes a netif on the
+    // tcpip thread - a DHCP renewal rewrites ip_addr, netmask and gw one after another - so the
+    // copy is taken through tcpip_call, where it can't observe a half-applied update. If the copy
+    // can't be queued (out of memory) the struct is read in place instead. Needs tcpip_init to
+    // have run: before that LwIP has no tcpip mailbox or core lock to take the copy with. On the
+    // tcpip thread itself it only works once that thread has been recorded (see tcpip_init);
+    // before then it deadlocks waiting on itself. The same goes for every public caller.
+    fn with<R>(&self, f: impl FnOnce(&netif) -> R) -> R {
+        let ptr = NetifPtr(self.0);
+        let snapshot = tcpip_call(move || NetifSnapshot(unsafe { *ptr.get() }))
+            .map(|snapshot| snapshot.0)
+            .unwrap_or_else(|| unsafe { *self.0 });
+        f(&snapshot)
+    }
+
+    // True once the interface has a usable address. These bindings come from a build without
+    // LWIP_IPV6 (netif has no ip6_addr array), so that means an IPv4 address, and there is no
+    // ipv6_ready: it could only ever return false.
+    pub fn is_initialised(&self) -> bool {
+        self.ipv4_ready()
+    }
+
+    pub fn ipv4_ready(&self) -> bool {
+        // Crude check that the interface is up by seeing if an IP address has been assigned.
+        // Unfortunately, LwIP does not provide a clean API function to do this. The address is a
+        // single aligned word that can't be read torn, so this reads it in place rather than
+        // through with(): it works before tcpip_init and on the tcpip thread, and stays cheap
+        // enough for wait_for_netif to poll.
+        unsafe { core::ptr::read_volatile(core::ptr::addr_of!((*self.0).ip_addr.addr)) != 0 }
+    }
+
+    // The address assigned to the interface (by DHCP or statically), if any. This, netmask and
+    // gateway go through with(), so see there before calling them on the tcpip thread. There is
+    // no IPv6 counterpart: without LWIP_IPV6 in these bindings netif has no ip6_addr array to list.
+    pub fn ipv4_addr(&self) -> Option<Ipv4Addr> {
+        self.with(|netif| ipv4_from_lwip(netif.ip_addr))
+    }
+
+    pub fn netmask(&self) -> Option<Ipv4Addr> {
+        self.with(|netif| ipv4_from_lwip(netif.netmask))
+    }
+
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        self.with(|netif| ipv4_from_lwip(netif.gw))
+    }
+}
+
+// netif pointers and copies to hand to and from the tcpip thread. netif statics live for the
+// life of the program, and the only pointer in a copy (next) is never followed through it.
+#[derive(Clone, Copy)]
+struct NetifPtr(*mut netif);
+unsafe impl Send for NetifPtr {}
+
+impl NetifPtr {
+    // A method rather than field access, so closures capture the whole (Send) wrapper.
+    fn get(self) -> *mut netif {
+        self.0
+    }
+}
+
+struct NetifSnapshot(netif);
+unsafe impl Send for NetifSnapshot {}
+
+// Shortcuts for the default interface.
+pub fn is_netif_initialised() -> bool {
+    Netif::default_interface().is_initialised()
+}
+
+pub fn netif_ipv4_ready() -> bool {
+    Netif::default_interface().ipv4_ready()
+}
+
+// Block the calling task until is_netif_initialised(), checking every 50 ms. Returns false if
+// `timeout` elapses first; None waits forever.
+pub fn wait_for_netif(timeout: Option<Duration>) -> bool {
+    const POLL_INTERVAL_MS: u32 = 50;
+
+    let start = unsafe { sys_now() };
+    let limit = timeout.map(|t| t.as_millis().min(u32::MAX as u128) as u32);
+    loop {
+        if is_netif_initialised() {
+            return true;
+        }
+        let mut sleep = POLL_INTERVAL_MS;
+        if let Some(limit) = limit {
+            let elapsed = unsafe { sys_now() }.wrapping_sub(start);
+            if elapsed >= limit {
+                return false;
+            }
+            sleep = sleep.min(limit - elapsed);
+        }
+        unsafe { sys_msleep(sleep) };
+    }
+}
+
+// LwIP keeps addresses in network byte order; None for the unset address 0.0.0.0.
+fn ipv4_from_lwip(addr: ip4_addr_t) -> Option<Ipv4Addr> {
+    match addr.addr {
+        0 => None,
+        raw => Some(Ipv4Addr::from(u32::from_be(raw))),
+    }
+}
+
+pub fn netif_ipv4_addr() -> Option<Ipv4Addr> {
+    Netif::default_interface().ipv4_addr()
+}
+
+pub fn netif_netmask() -> Option<Ipv4Addr> {
+    Netif::default_interface().netmask()
+}
+
+pub fn netif_gateway() -> Option<Ipv4Addr> {
+    Netif::default_interface().gateway()
+}
+
+// Network bring-up stage as far as DHCP is concerned. A lease that is being renewed or rebound
+// still reports Bound: the address stays in use throughout.
+// There is deliberately no lease-time accessor. The lease times live in struct dhcp, reached
+// through the netif_dhcp_data() macro (a netif client_data slot), and these bindings have neither
+// that struct nor the netif fields behind the macro. Guessing the layout would read garbage on
+// any build configured differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpState {
+    // The DHCP client isn't running; any address is statically configured.
+    Off,
+    // Waiting for a lease, with no address yet.
+    Requesting,
+    Bound,
+}
+
+// Whether the DHCP client on the default interface was last started or stopped through this crate
+// (start_dhcp, or stop_dhcp/set_static_ipv4), or neither.
+const DHCP_UNKNOWN: u8 = 0;
+const DHCP_STARTED: u8 = 1;
+const DHCP_STOPPED: u8 = 2;
+static DEFAULT_DHCP: AtomicU8 = AtomicU8::new(DHCP_UNKNOWN);
+
+impl Netif {
+    // struct dhcp (netif_dhcp_data) isn't in these bindings, so this combines
+    // dhcp_supplied_address with whether the client was started or stopped through this crate.
+    // For an interface the crate hasn't touched, a missing address is taken to mean the client is
+    // still requesting one, which is how the port brings interfaces up. Goes through tcpip_call,
+    // with the same tcpip-thread precondition as with().
+    pub fn dhcp_state(&self) -> DhcpState {
+        let control = if self.0 == Netif::default_interface().0 {
+            DEFAULT_DHCP.load(Ordering::Relaxed)
+        } else {
+            DHCP_UNKNOWN
+        };
+        let ptr = NetifPtr(self.0);
+        let state = tcpip_call(move || unsafe {
+            let netif = ptr.get();
+            (
+                dhcp_supplied_address(netif) != 0,
+                (*netif).ip_addr.addr != 0,
+            )
+        });
+        match (state.unwrap_or((false, self.ipv4_ready())), control) {
+            ((true, _), _) => DhcpState::Bound,
+            (_, DHCP_STOPPED) => DhcpState::Off,
+            (_, DHCP_STARTED) => DhcpState::Requesting,
+            ((false, true), _) => DhcpState::Off,
+            ((false, false), _) => DhcpState::Requesting,
+        }
+    }
+}
+
+pub fn dhcp_state() -> DhcpState {
+    Netif::default_interface().dhcp_state()
+}
+
+// There are no interface statistics. The MIB2 counters (netif->mib2_counters) only exist when
+// LwIP is built with MIB2_STATS, and the netif struct in these bindings was generated without
+// them, so there is nothing to read.
+
+// There are no MTU or MAC-address getters. The netif struct in these bindings stops after the
+// IPv4 addresses, so the offsets of mtu, hwaddr_len and hwaddr aren't known, and reading them
+// would mean guessing at the C layout. They can be added once the bindings are regenerated with
+// the full struct.
+
+// There are no hostname accessors. netif_get_hostname/netif_set_hostname are macros over
+// netif->hostname, a field that only exists with LWIP_NETIF_HOSTNAME, and the netif struct in
+// these bindings was generated without it. They can be added once the bindings come from a build
+// with the option.
+
+pub fn shutdown(sock: RawSocket, how: c_int) -> i32 {
+    unsafe { lwip_shutdown(sock, how) }
+}
+
+// Mirrors std::net::Shutdown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownMode {
+    Read,
+    Write,
+    Both,
+}
+
+pub fn shutdown_socket(sock: RawSocket, how: ShutdownMode) -> Result<()> {
+    let how = match how {
+        ShutdownMode::Read => SHUT_RD,
+        ShutdownMode::Write => SHUT_WR,
+        ShutdownMode::Both => SHUT_RDWR,
+    };
+    match shutdown(sock, how) {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+// lwip_poll writes revents back into `fds`, hence *mut (the generated binding had *const).
+pub fn poll(fds: *mut pollfd, nfds: nfds_t, timeout: core::ffi::c_int) -> i32 {
+    unsafe { lwip_poll(fds, nfds, timeout) }
+}
+
+// Ready count, or the errno on failure. None waits indefinitely; timeouts too long for a c_int of
+// milliseconds saturate.
+pub fn poll_fds(fds: &mut [pollfd], timeout: Option<Duration>) -> Result<usize> {
+    let timeout = match timeout {
+        Some(t) => t.as_millis().min(c_int::MAX as u128) as c_int,
+        None => -1,
+    };
+    match poll(fds.as_mut_ptr(), fds.len() as nfds_t, timeout) {
+        ready if ready < 0 => Err(Error::last()),
+        ready => Ok(ready as usize),
+    }
+}
+
+pub fn fcntl(s: core::ffi::c_int, cmd: core::ffi::c_int, val: core::ffi::c_int) -> i32 {
+    unsafe { lwip_fcntl(s, cmd, val) }
+}
+
+pub fn ioctl(s: core::ffi::c_int, cmd: core::ffi::c_long, argp: *mut core::ffi::c_void) -> i32 {
+    unsafe { lwip_ioctl(s, cmd, argp) }
+}
+
+// Uses fcntl's O_NONBLOCK flag, falling back to the FIONBIO ioctl on builds where fcntl reports
+// ENOSYS.
+pub fn set_nonblocking(sock: RawSocket, nonblocking: bool) -> Result<()> {
+    let flags = fcntl(sock, F_GETFL, 0);
+    if flags < 0 {
+        return match last_errno() {
+            ENOSYS => set_nonblocking_ioctl(sock, nonblocking),
+            errno => Err(Error::from_errno(errno)),
+        };
+    }
+    let flags = match nonblocking {
+        true => flags | O_NONBLOCK,
+        false => flags & !O_NONBLOCK,
+    };
+    match fcntl(sock, F_SETFL, flags) {
+        0 => Ok(()),
+        _ => match last_errno() {
+            ENOSYS => set_nonblocking_ioctl(sock, nonblocking),
+            errno => Err(Error::from_errno(errno)),
+        },
+    }
+}
+
+pub fn set_nonblocking_ioctl(sock: RawSocket, nonblocking: bool) -> Result<()> {
+    let mut value: c_int = nonblocking as c_int;
+    match ioctl(sock, FIONBIO, &mut value as *mut c_int as *mut c_void) {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+pub fn is_nonblocking(sock: RawSocket) -> Result<bool> {
+    match fcntl(sock, F_GETFL, 0) {
+        flags if flags < 0 => Err(Error::last()),
+        flags => Ok(flags & O_NONBLOCK != 0),
+    }
+}
+
+// Bytes queued for reading (FIONREAD). On a datagram socket LwIP counts every queued datagram,
+// not just the next one. Needs LwIP built with LWIP_SO_RCVBUF, otherwise this fails with ENOSYS.
+pub fn bytes_available(sock: RawSocket) -> Result<usize> {
+    let mut queued: c_int = 0;
+    match ioctl(sock, FIONREAD, &mut queued as *mut c_int as *mut c_void) {
+        0 => Ok(queued.max(0) as usize),
+        _ => Err(Error::last()),
+    }
+}
+
+// Per-socket priority boosts, consulted around blocking calls. The flag keeps the common case
+// (no boosts registered) down to a single relaxed load.
+static PRIORITY_BOOSTS: Mutex<Vec<(RawSocket, c_ulong)>> = Mutex::new(Vec::new());
+static PRIORITY_BOOST_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Opt-in: while a task is inside a blocking connect/accept/send/recv call on `sock`, raise its
+// FreeRTOS priority to `level` so it isn't preempted mid-call while LwIP holds internal state on
+// its behalf (the source of jitter on real-time control links). The boost only ever raises the
+// priority - a task already running at or above `level`, including via priority inheritance, is
+// left alone - and the task's base priority (not any inherited one) is restored when the call
+// returns. FreeRTOS clamps `level` to configMAX_PRIORITIES - 1.
+// Keep `level` at or below the tcpip thread's priority (TCPIP_THREAD_PRIO), otherwise the boosted
+// task can starve the very thread it is waiting on. A `level` of 0 removes the boost.
+pub fn priority_boost(sock: RawSocket, level: u32) {
+    let mut boosts = PRIORITY_BOOSTS.lock().unwrap_or_else(|e| e.into_inner());
+    boosts.retain(|&(s, _)| s != sock);
+    if level != 0 {
+        boosts.push((sock, level as c_ulong));
+    }
+    PRIORITY_BOOST_ACTIVE.store(!boosts.is_empty(), Ordering::Relaxed);
+}
+
+fn with_priority_boost<R>(sock: RawSocket, call: impl FnOnce() -> R) -> R {
+    if !PRIORITY_BOOST_ACTIVE.load(Ordering::Relaxed) {
+        return call();
+    }
+    let level = PRIORITY_BOOSTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|&&(s, _)| s == sock)
+        .map(|&(_, level)| level);
+    let Some(level) = level else {
+        return call();
+    };
+    unsafe {
+        if level <= uxTaskPriorityGet(core::ptr::null()) {
+            return call();
+        }
+        // Save the base priority rather than the effective one: if the task currently holds a
+        // mutex with an inherited priority, writing that back would make the boost permanent.
+        let base = uxTaskBasePriorityGet(core::ptr::null());
+        vTaskPrioritySet(core::ptr::null_mut(), level);
+        let retval = call();
+        vTaskPrioritySet(core::ptr::null_mut(), base);
+        retval
+    }
+}
+
+// Cheap liveness probe for an idle (pooled / keep-alive) TCP connection, meant to be called
+// before reuse. A connection is unhealthy if it is no longer connected to a peer, is flagged
+// errored/hung-up by poll (which is how a pending socket error shows), or the peer has performed
+// an orderly close. The probe changes nothing: SO_ERROR isn't read, since that would clear the
+// pending error a later connect_result/take_error needs, and queued unread data counts as healthy
+// and is left in place - only a MSG_PEEK is ever issued.
+pub fn is_healthy(sock: RawSocket) -> bool {
+    let mut peer: sockaddr_storage = unsafe { core::mem::zeroed() };
+    let mut peer_len = core::mem::size_of::<sockaddr_storage>() as socklen_t;
+    if getpeername(
+        sock,
+        &mut peer as *mut sockaddr_storage as *mut sockaddr,
+        &mut peer_len,
+    ) != 0
+    {
+        return false;
+    }
+
+    let mut fds = [pollfd {
+        fd: sock,
+        events: POLLIN,
+        revents: 0,
+    }];
+    if poll(fds.as_mut_ptr(), 1, 0) < 0 {
+        return false;
+    }
+    let revents = fds[0].revents;
+    if revents & (POLLERR | POLLHUP | POLLNVAL) != 0 {
+        return false;
+    }
+    if revents & POLLIN == 0 {
+        // Idle with nothing queued.
+        return true;
+    }
+
+    // Readable: either data is queued or the peer has closed (zero-length read).
+    let mut byte: u8 = 0;
+    recv(
+        sock,
+        &mut byte as *mut u8 as *mut c_void,
+        1,
+        MSG_PEEK | MSG_DONTWAIT,
+    ) > 0
+}
+
+// Enable or disable local loopback of IPv4 multicast datagrams sent from this socket, so a sender
+// on the same host can choose whether it receives its own traffic. LwIP stores this as a u8_t.
+// IP_MULTICAST_LOOP is only handled with LWIP_MULTICAST_TX_OPTIONS; otherwise this fails with
+// ENOPROTOOPT. Looped datagrams also need a loopback-capable build (LWIP_NETIF_LOOPBACK or
+// LWIP_HAVE_LOOPIF), or enabling this has no visible effect. Unlike most stacks LwIP starts with
+// loopback off. There is no IPv6 counterpart; see set_multicast_loop_v6.
+pub fn set_multicast_loop_v4(sock: RawSocket, enable: bool) -> Result<()> {
+    setsockopt_u8(sock, IPPROTO_IP, IP_MULTICAST_LOOP, enable as u8_t)
+}
+
+// Hop limit for outgoing IPv4 multicast datagrams; 1 (LwIP's default) keeps them on the local
+// segment. Also read by LwIP as a u8_t.
+pub fn set_multicast_ttl_v4(sock: RawSocket, ttl: u8) -> Result<()> {
+    setsockopt_u8(sock, IPPROTO_IP, IP_MULTICAST_TTL, ttl)
+}
+
+fn setsockopt_u8(sock: RawSocket, level: c_int, optname: c_int, value: u8_t) -> Result<()> {
+    let retval = setsockopt(
+        sock,
+        level,
+        optname,
+        &value as *const u8_t as *const c_void,
+        core::mem::size_of::<u8_t>() as socklen_t,
+    );
+    match retval {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+// Join or leave an IPv4 multicast group on the interface with address `interface`. Left to
+// itself LwIP treats 0.0.0.0 as "every interface"; here it means the default netif, matching
+// other stacks, and only falls through to LwIP as 0.0.0.0 while that interface has no address.
+// Needs LwIP built with LWIP_IGMP.
+pub fn join_multicast_v4(sock: RawSocket, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+    multicast_membership_v4(sock, IP_ADD_MEMBERSHIP, multiaddr, interface)
+}
+
+pub fn leave_multicast_v4(sock: RawSocket, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+    multicast_membership_v4(sock, IP_DROP_MEMBERSHIP, multiaddr, interface)
+}
+
+fn multicast_membership_v4(
+    sock: RawSocket,
+    optname: c_int,
+    multiaddr: Ipv4Addr,
+    interface: Ipv4Addr,
+) -> Result<()> {
+    let interface = match interface.is_unspecified() {
+        true => netif_ipv4_addr().unwrap_or(Ipv4Addr::UNSPECIFIED),
+        false => interface,
+    };
+    let mreq = ip_mreq {
+        imr_multiaddr: in_addr {
+            s_addr: u32::from(multiaddr).to_be(),
+        },
+        imr_interface: in_addr {
+            s_addr: u32::from(interface).to_be(),
+        },
+    };
+    let retval = setsockopt(
+        sock,
+        IPPROTO_IP,
+        optname,
+        &mreq as *const ip_mreq as *const c_void,
+        core::mem::size_of::<ip_mreq>() as socklen_t,
+    );
+    match retval {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+// Join or leave an IPv6 multicast group on the interface with index `interface` (0 lets LwIP
+// pick). Needs LwIP built with LWIP_IPV6 and LWIP_IPV6_MLD; these bindings come from a build
+// without IPv6, where LwIP rejects the IPPROTO_IPV6 level with ENOPROTOOPT.
+#[cfg(feature = "ipv6")]
+pub fn join_multicast_v6(
+    sock: RawSocket,
+    multiaddr: std::net::Ipv6Addr,
+    interface: u32,
+) -> Result<()> {
+    multicast_membership_v6(sock, IPV6_JOIN_GROUP, multiaddr, interface)
+}
+
+#[cfg(feature = "ipv6")]
+pub fn leave_multicast_v6(
+    sock: RawSocket,
+    multiaddr: std::net::Ipv6Addr,
+    interface: u32,
+) -> Result<()> {
+    multicast_membership_v6(sock, IPV6_LEAVE_GROUP, multiaddr, interface)
+}
+
+#[cfg(feature = "ipv6")]
+fn multicast_membership_v6(
+    sock: RawSocket,
+    optname: c_int,
+    multiaddr: std::net::Ipv6Addr,
+    interface: u32,
+) -> Result<()> {
+    let mreq = ipv6_mreq {
+        ipv6mr_multiaddr: in6_addr {
+            s6_addr: multiaddr.octets(),
+        },
+        ipv6mr_interface: interface,
+    };
+    let retval = setsockopt(
+        sock,
+        IPPROTO_IPV6,
+        optname,
+        &mreq as *const ipv6_mreq as *const c_void,
+        core::mem::size_of::<ipv6_mreq>() as socklen_t,
+    );
+    match retval {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+// LwIP implements neither IPV6_MULTICAST_LOOP (see the stub constant above) nor
+// IPV6_MULTICAST_HOPS, in any configuration: outgoing multicast always uses the netif's hop limit.
+// These fail with EOPNOTSUPP so callers can treat both families the same way without mistaking
+// the option for applied.
+#[cfg(feature = "ipv6")]
+pub fn set_multicast_loop_v6(_sock: RawSocket, _enable: bool) -> Result<()> {
+    Err(Error::from_errno(EOPNOTSUPP))
+}
+
+#[cfg(feature = "ipv6")]
+pub fn set_multicast_hops_v6(_sock: RawSocket, _hops: u32) -> Result<()> {
+    Err(Error::from_errno(EOPNOTSUPP))
+}
+
+// Value of a socket option passed to apply_options. Int covers the usual c_int options; Byte is
+// for the handful LwIP reads as u8_t (IP_MULTICAST_TTL, IP_MULTICAST_LOOP); Bytes passes an
+// arbitrary buffer (struct options such as linger or ip_mreq) through unchanged.
+#[derive(Debug, Clone, Copy)]
+pub enum OptValue<'a> {
+    Int(c_int),
+    Byte(u8),
+    Bytes(&'a [u8]),
+}
+
+// Apply a list of (level, name, value) socket options in one call, e.g. from a config table.
+// Every option is attempted; on failure the (level, name) pairs that LwIP rejected are returned in
+// the order given, each with the error it failed with, rather than a single -1 for the whole
+// batch.
+pub fn apply_options(
+    sock: RawSocket,
+    opts: &[(c_int, c_int, OptValue)],
+) -> Result<(), Vec<(c_int, c_int, Error)>> {
+    let mut failed = Vec::new();
+    for &(level, optname, value) in opts {
+        let retval = match value {
+            OptValue::Int(v) => setsockopt(
+                sock,
+                level,
+                optname,
+                &v as *const c_int as *const c_void,
+                core::mem::size_of::<c_int>() as socklen_t,
+            ),
+            OptValue::Byte(v) => setsockopt(
+                sock,
+                level,
+                optname,
+                &v as *const u8 as *const c_void,
+                core::mem::size_of::<u8>() as socklen_t,
+            ),
+            OptValue::Bytes(v) => setsockopt(
+                sock,
+                level,
+                optname,
+                v.as_ptr() as *const c_void,
+                v.len() as socklen_t,
+            ),
+        };
+        if retval != 0 {
+            failed.push((level, optname, Error::last()));
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(failed)
+    }
+}
+
+// recvfrom() with the source address written to a sockaddr_storage, and fromlen sized to match,
+// so the caller doesn't have to commit to sockaddr_in or sockaddr_in6 up front. Passing a
+// family-specific buffer can silently truncate the address when the datagram arrives from the
+// other family. Returns the recvfrom result and the valid length of `from`; check
+// `from.ss_family` to decide how to interpret it.
+pub fn recvfrom_storage(
+    sock: RawSocket,
+    mem: *mut c_void,
+    len: i32,
+    flags: c_int,
+    from: &mut sockaddr_storage,
+) -> (i32, socklen_t) {
+    let mut fromlen = core::mem::size_of::<sockaddr_storage>() as socklen_t;
+    let retval = recvfrom(
+        sock,
+        mem,
+        len,
+        flags,
+        from as *mut sockaddr_storage as *mut sockaddr,
+        &mut fromlen,
+    );
+    (retval, fromlen)
+}
+
+// What a drain_ready pass delivered: the bytes handed to the sink, and whether it stopped because
+// the peer closed the connection rather than because nothing more was queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Drained {
+    pub bytes: usize,
+    pub eof: bool,
+}
+
+// Edge-triggered read helper for event loops: while poll() reports the socket readable, recv()
+// without blocking and hand each chunk to `sink`. Stops as soon as the socket is no longer ready,
+// on end-of-stream (reported as `eof`), or on error, so it never spins. An error only fails the
+// call if nothing had been delivered yet; otherwise the bytes are reported and the error is left
+// for the next call to see.
+pub fn drain_ready(sock: RawSocket, mut sink: impl FnMut(&[u8])) -> Result<Drained> {
+    let mut buf = [0u8; 1024];
+    let mut drained = Drained::default();
+    loop {
+        let mut fds = [pollfd {
+            fd: sock,
+            events: POLLIN,
+            revents: 0,
+        }];
+        match poll(fds.as_mut_ptr(), 1, 0) {
+            0 => break,
+            n if n < 0 && drained.bytes == 0 => return Err(Error::last()),
+            n if n < 0 => break,
+            _ => {}
+        }
+        if fds[0].revents & POLLIN == 0 {
+            if drained.bytes == 0 {
+                if fds[0].revents & POLLNVAL != 0 {
+                    return Err(Error::from_errno(EBADF));
+                }
+                if fds[0].revents & POLLERR != 0 {
+                    if let Some(err) = take_error(sock)? {
+                        return Err(err);
+                    }
+                }
+            }
+            break;
+        }
+        let n = recv(
+            sock,
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len() as i32,
+            MSG_DONTWAIT,
+        );
+        if n == 0 {
+            drained.eof = true;
+            break;
+        }
+        if n < 0 {
+            // EWOULDBLOCK means we lost a race with another reader: nothing is left to drain.
+            let err = Error::last();
+            if drained.bytes == 0 && err.kind() != ErrorKind::WouldBlock {
+                return Err(err);
+            }
+            break;
+        }
+        sink(&buf[..n as usize]);
+        drained.bytes += n as usize;
+    }
+    Ok(drained)
+}
+
+// listen() with the backlog LwIP will actually use reported back. lwip_listen clamps the backlog
+// to 0..=0xff and stores it as a u8_t, and tcp_backlog_set then turns 0 into 1, so the result is
+// always in 1..=0xff. It is only enforced if LwIP was built with TCP_LISTEN_BACKLOG, otherwise
+// pending connections are limited only by MEMP_NUM_TCP_PCB.
+// A negative backlog is rejected with InvalidInput rather than letting it silently become 1.
+pub fn listen_checked(sock: RawSocket, backlog: c_int) -> Result<u8> {
+    let effective = effective_backlog(backlog)?;
+    match listen(sock, effective as c_int) {
+        0 => Ok(effective),
+        _ => Err(Error::last()),
+    }
+}
+
+fn effective_backlog(backlog: c_int) -> Result<u8> {
+    if backlog < 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "negative listen backlog",
+        ));
+    }
+    Ok(backlog.clamp(1, 0xff) as u8)
+}
+
+// Deadline tracker for event loops that manage many per-connection timeouts (idle timeouts and
+// the like) without a timer per connection. Deadlines are sys_now() ticks; internally the clock is
+// extended to 64 bits so ordering survives sys_now wraparound, provided the wheel is consulted
+// (expired() or poll_timeout()) at least once per wrap period. Each pass through the poll loop:
+// call poll_timeout() for the poll() timeout, then expired() for the ids whose deadline has passed.
+pub struct TimerWheel<K = RawSocket> {
+    deadlines: BTreeSet<(u64, K)>,
+    by_id: BTreeMap<K, u64>,
+    last_tick: u32,
+    elapsed: u64,
+}
+
+impl<K: Ord + Copy> TimerWheel<K> {
+    pub fn new() -> Self {
+        Self::new_at(unsafe { sys_now() })
+    }
+
+    fn new_at(now: u32) -> Self {
+        TimerWheel {
+            deadlines: BTreeSet::new(),
+            by_id: BTreeMap::new(),
+            last_tick: now,
+            elapsed: 0,
+        }
+    }
+
+    // Arm (or re-arm) the timeout for `id` at the sys_now() tick `deadline`. A deadline that is
+    // already in the past expires on the next call to expired().
+    pub fn register(&mut self, id: K, deadline: u32) {
+        self.register_at(id, deadline, unsafe { sys_now() });
+    }
+
+    // Arm (or re-arm) the timeout for `id` to expire `timeout` from now.
+    pub fn register_after(&mut self, id: K, timeout: Duration) {
+        let now = unsafe { sys_now() };
+        let ms = timeout.as_millis().min(i32::MAX as u128) as u32;
+        self.register_at(id, now.wrapping_add(ms), now);
+    }
+
+    fn register_at(&mut self, id: K, deadline: u32, now: u32) {
+        let now = self.advance(now);
+        // Interpret the deadline relative to the current tick, the same way LwIP compares times.
+        let delta = deadline.wrapping_sub(self.last_tick) as i32;
+        let at = if delta < 0 {
+            now.saturating_sub(delta.unsigned_abs() as u64)
+        } else {
+            now + delta as u64
+        };
+        self.cancel(id);
+        self.deadlines.insert((at, id));
+        self.by_id.insert(id, at);
+    }
+
+    // Disarm the timeout for `id`. Returns false if none was registered.
+    pub fn cancel(&mut self, id: K) -> bool {
+        match self.by_id.remove(&id) {
+            Some(at) => self.deadlines.remove(&(at, id)),
+            None => false,
+        }
+    }
+
+    // Remove and return every id whose deadline has been reached, earliest deadline first.
+    pub fn expired(&mut self) -> Vec<K> {
+        self.expired_at(unsafe { sys_now() })
+    }
+
+    fn expired_at(&mut self, now: u32) -> Vec<K> {
+        let now = self.advance(now);
+        let mut ids = Vec::new();
+        while let Some(&(at, id)) = self.deadlines.first() {
+            if at > now {
+                break;
+            }
+            self.deadlines.remove(&(at, id));
+            self.by_id.remove(&id);
+            ids.push(id);
+        }
+        ids
+    }
+
+    // Timeout to pass to poll(): milliseconds until the nearest deadline, 0 if one is already due,
+    // or -1 (wait indefinitely) when nothing is registered.
+    pub fn poll_timeout(&mut self) -> c_int {
+        self.poll_timeout_at(unsafe { sys_now() })
+    }
+
+    fn poll_timeout_at(&mut self, now: u32) -> c_int {
+        let now = self.advance(now);
+        match self.deadlines.first() {
+            Some(&(at, _)) => at.saturating_sub(now).min(c_int::MAX as u64) as c_int,
+            None => -1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+
+    // Fold the latest sys_now() reading into the 64-bit clock.
+    fn advance(&mut self, now: u32) -> u64 {
+        self.elapsed += now.wrapping_sub(self.last_tick) as u64;
+        self.last_tick = now;
+        self.elapsed
+    }
+}
+
+impl<K: Ord + Copy> Default for TimerWheel<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// LwIP's compiled-in keepalive defaults (TCP_KEEPIDLE_DEFAULT, TCP_KEEPINTVL_DEFAULT,
+// TCP_KEEPCNT_DEFAULT), reported when a sub-option can't be read back from the socket.
+const TCP_KEEPIDLE_DEFAULT_MS: u64 = 7_200_000;
+const TCP_KEEPINTVL_DEFAULT_MS: u64 = 75_000;
+const TCP_KEEPCNT_DEFAULT: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveParams {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+fn getsockopt_int(sock: RawSocket, level: c_int, optname: c_int) -> Option<c_int> {
+    get_sockopt_int(sock, level, optname).ok()
+}
+
+// Current keepalive configuration: None when SO_KEEPALIVE is off, otherwise the idle time,
+// probe interval and probe count. LwIP reports TCP_KEEPIDLE/TCP_KEEPINTVL in seconds; those are
+// only readable when LwIP is built with LWIP_TCP_KEEPALIVE, and any that can't be read fall back
+// to the pcb defaults. Fails only if SO_KEEPALIVE itself can't be read.
+pub fn keepalive(sock: RawSocket) -> Result<Option<KeepaliveParams>> {
+    let enabled = get_sockopt_int(sock, SOL_SOCKET, SO_KEEPALIVE)?;
+    if enabled == 0 {
+        return Ok(None);
+    }
+    let seconds = |optname, default_ms| {
+        getsockopt_int(sock, IPPROTO_TCP, optname)
+            .map(|s| Duration::from_secs(s.max(0) as u64))
+            .unwrap_or(Duration::from_millis(default_ms))
+    };
+    Ok(Some(KeepaliveParams {
+        idle: seconds(TCP_KEEPIDLE, TCP_KEEPIDLE_DEFAULT_MS),
+        interval: seconds(TCP_KEEPINTVL, TCP_KEEPINTVL_DEFAULT_MS),
+        count: getsockopt_int(sock, IPPROTO_TCP, TCP_KEEPCNT)
+            .map(|c| c.max(0) as u32)
+            .unwrap_or(TCP_KEEPCNT_DEFAULT),
+    }))
+}
+
+// Turn keepalive off (None) or on with the given idle time, probe interval and probe count - the
+// counterpart of keepalive(). TCP_KEEPIDLE/TCP_KEEPINTVL take whole seconds in LwIP (it multiplies
+// by 1000 internally), so the durations are rounded up to the next second. The three timing
+// options only exist when LwIP is built with LWIP_TCP_KEEPALIVE; without it this fails with
+// ENOPROTOOPT after enabling keepalive.
+pub fn set_keepalive(sock: RawSocket, params: Option<KeepaliveParams>) -> Result<()> {
+    set_sockopt_int(sock, SOL_SOCKET, SO_KEEPALIVE, params.is_some() as i32)?;
+    let Some(params) = params else {
+        return Ok(());
+    };
+    let seconds = |d: Duration| {
+        let secs = d.as_secs() + (d.subsec_nanos() > 0) as u64;
+        secs.min(i32::MAX as u64) as i32
+    };
+    set_sockopt_int(sock, IPPROTO_TCP, TCP_KEEPIDLE, seconds(params.idle))?;
+    set_sockopt_int(sock, IPPROTO_TCP, TCP_KEEPINTVL, seconds(params.interval))?;
+    set_sockopt_int(
+        sock,
+        IPPROTO_TCP,
+        TCP_KEEPCNT,
+        params.count.min(i32::MAX as u32) as i32,
+    )
+}
+
+// Format a buffer the way `tcpdump -X` does, for use when debugging protocols on the device:
+// one line per 16 bytes with the offset, the bytes as space-separated 16-bit hex groups, and an
+// ASCII column with non-printable bytes shown as '.'. A short final line is padded so its ASCII
+// column lines up with the lines above. An empty buffer produces an empty string.
+pub fn hex_dump(buf: &[u8]) -> String {
+    use std::fmt::Write;
+
+    const BYTES_PER_LINE: usize = 16;
+    // Eight 4-digit groups separated by single spaces.
+    const HEX_WIDTH: usize = 8 * 4 + 7;
+
+    let mut out = String::new();
+    for (line, chunk) in buf.chunks(BYTES_PER_LINE).enumerate() {
+        let mut hex = String::with_capacity(HEX_WIDTH);
+        for (i, pair) in chunk.chunks(2).enumerate() {
+            if i > 0 {
+                hex.push(' ');
+            }
+            for byte in pair {
+                let _ = write!(hex, "{:02x}", byte);
+            }
+        }
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(
+            out,
+            "0x{:04x}:  {:<width$}  {}",
+            line * BYTES_PER_LINE,
+            hex,
+            ascii,
+            width = HEX_WIDTH
+        );
+    }
+    out
+}
+
+// Port-only address lookups for hot logging paths. sin_port and sin6_port sit at the same offset,
+// and LwIP truncates the copied address to the buffer given, so a sockaddr_in is enough for either
+// family. Ports are returned in host order; the lookup fails with e.g. ENOTCONN for peer_port on
+// an unconnected socket.
+pub fn local_port(sock: RawSocket) -> Result<u16> {
+    let mut addr: sockaddr_in = unsafe { core::mem::zeroed() };
+    let mut len = core::mem::size_of::<sockaddr_in>() as socklen_t;
+    match getsockname(
+        sock,
+        &mut addr as *mut sockaddr_in as *mut sockaddr,
+        &mut len,
+    ) {
+        0 => Ok(u16::from_be(addr.sin_port)),
+        _ => Err(Error::last()),
+    }
+}
+
+pub fn peer_port(sock: RawSocket) -> Result<u16> {
+    let mut addr: sockaddr_in = unsafe { core::mem::zeroed() };
+    let mut len = core::mem::size_of::<sockaddr_in>() as socklen_t;
+    match getpeername(
+        sock,
+        &mut addr as *mut sockaddr_in as *mut sockaddr,
+        &mut len,
+    ) {
+        0 => Ok(u16::from_be(addr.sin_port)),
+        _ => Err(Error::last()),
+    }
+}
+
+// Optional LwIP build options that the socket API depends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Ipv6,         // LWIP_IPV6
+    Igmp,         // LWIP_IGMP (IPv4 multicast membership)
+    RecvTimeout,  // LWIP_SO_RCVTIMEO
+    SendTimeout,  // LWIP_SO_SNDTIMEO
+    ReuseAddr,    // SO_REUSE
+    RecvBuffer,   // LWIP_SO_RCVBUF
+    Linger,       // LWIP_SO_LINGER
+    TcpKeepalive, // LWIP_TCP_KEEPALIVE
+}
+
+// Which optional build options the linked LwIP provides. LWIP_DHCP and friends aren't visible
+// through the socket layer and so aren't reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    pub ipv6: bool,
+    pub igmp: bool,
+    pub recv_timeout: bool,
+    pub send_timeout: bool,
+    pub reuse_addr: bool,
+    pub recv_buffer: bool,
+    pub linger: bool,
+    pub tcp_keepalive: bool,
+}
+
+impl Capabilities {
+    pub fn has(&self, cap: Capability) -> bool {
+        match cap {
+            Capability::Ipv6 => self.ipv6,
+            Capability::Igmp => self.igmp,
+            Capability::RecvTimeout => self.recv_timeout,
+            Capability::SendTimeout => self.send_timeout,
+            Capability::ReuseAddr => self.reuse_addr,
+            Capability::RecvBuffer => self.recv_buffer,
+            Capability::Linger => self.linger,
+            Capability::TcpKeepalive => self.tcp_keepalive,
+        }
+    }
+}
+
+// The capabilities that require() found missing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingCapability(pub Vec<Capability>);
+
+impl core::fmt::Display for MissingCapability {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "LwIP build is missing {:?}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MissingCapability {}
+
+static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+
+// Report which optional LwIP options are compiled in. IPv6 is known from the bindings (AF_INET6
+// collapses to AF_UNSPEC without LWIP_IPV6); the rest are detected once, on first call, by
+// reading each option back on throwaway UDP and TCP sockets - LwIP rejects options it wasn't
+// built with. The probe needs two free sockets; if either can't be opened (the pool is
+// exhausted, or tcpip_init hasn't run yet) the options it would have tested read as missing, and
+// that result isn't cached, so a later call probes again.
+pub fn capabilities() -> Capabilities {
+    if let Some(caps) = CAPABILITIES.get() {
+        return *caps;
+    }
+    let (caps, complete) = probe_capabilities();
+    if complete {
+        let _ = CAPABILITIES.set(caps);
+    }
+    caps
+}
+
+// Check up front that the LwIP build has everything the caller relies on, instead of finding
+// out from a -1/EOPNOTSUPP later.
+pub fn require(caps: &[Capability]) -> Result<(), MissingCapability> {
+    let found = capabilities();
+    let missing: Vec<Capability> = caps.iter().copied().filter(|&c| !found.has(c)).collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(MissingCapability(missing))
+    }
+}
+
+// The detected capabilities, and whether both probe sockets could be opened.
+fn probe_capabilities() -> (Capabilities, bool) {
+    // Large enough for any of the probed options (timeval is the biggest).
+    let readable = |sock: RawSocket, level: c_int, optname: c_int| {
+        let mut buf = [0u8; 16];
+        let mut len = buf.len() as socklen_t;
+        getsockopt(
+            sock,
+            level,
+            optname,
+            buf.as_mut_ptr() as *mut c_void,
+            &mut len,
+        ) == 0
+    };
+
+    let mut caps = Capabilities {
+        ipv6: AF_INET6 != AF_UNSPEC,
+        ..Capabilities::default()
+    };
+
+    let udp = socket(AF_INET, SOCK_DGRAM, IPPROTO_UDP);
+    if udp >= 0 {
+        caps.recv_timeout = readable(udp, SOL_SOCKET, SO_RCVTIMEO);
+        caps.send_timeout = readable(udp, SOL_SOCKET, SO_SNDTIMEO);
+        caps.reuse_addr = readable(udp, SOL_SOCKET, SO_REUSEADDR);
+        caps.recv_buffer = readable(udp, SOL_SOCKET, SO_RCVBUF);
+
+        // Leaving a group that was never joined fails with EADDRNOTAVAIL when IGMP is built in,
+        // and ENOPROTOOPT when it isn't.
+        let mreq = ip_mreq {
+            imr_multiaddr: in_addr {
+                s_addr: u32::from_be_bytes([239, 255, 255, 250]).to_be(),
+            },
+            imr_interface: in_addr { s_addr: 0 },
+        };
+        let retval = setsockopt(
+            udp,
+            IPPROTO_IP,
+            IP_DROP_MEMBERSHIP,
+            &mreq as *const ip_mreq as *const c_void,
+            core::mem::size_of::<ip_mreq>() as socklen_t,
+        );
+        caps.igmp = retval == 0 || last_errno() != ENOPROTOOPT;
+        close(udp);
+    }
+
+    let tcp = socket(AF_INET, SOCK_STREAM, IPPROTO_TCP);
+    if tcp >= 0 {
+        caps.linger = readable(tcp, SOL_SOCKET, SO_LINGER);
+        caps.tcp_keepalive = readable(tcp, IPPROTO_TCP, TCP_KEEPIDLE);
+        close(tcp);
+    }
+
+    (caps, udp >= 0 && tcp >= 0)
+}
+
+// LwIP's limit on the number of iovecs in one sendmsg/recvmsg (IOV_MAX in lwip/sockets.h).
+pub const IOV_MAX: usize = 0xFFFF;
+
+fn sockaddr_in_from(addr: &SocketAddrV4) -> sockaddr_in {
+    sockaddr_in {
+        sin_len: core::mem::size_of::<sockaddr_in>() as u8_t,
+        sin_family: AF_INET as sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    }
+}
+
+#[cfg(feature = "ipv6")]
+fn sockaddr_in6_from(addr: &SocketAddrV6) -> sockaddr_in6 {
+    sockaddr_in6 {
+        sin6_len: core::mem::size_of::<sockaddr_in6>() as u8_t,
+        sin6_family: AF_INET6 as sa_family_t,
+        sin6_port: addr.port().to_be(),
+        sin6_flowinfo: addr.flowinfo().to_be(),
+        sin6_addr: in6_addr {
+            s6_addr: addr.ip().octets(),
+        },
+        sin6_scope_id: addr.scope_id(),
+    }
+}
+
+// IPv6 support needs both halves enabled together: LWIP_IPV6 in the LwIP build the bindings come
+// from (which makes AF_INET6 a family of its own) and this crate's `ipv6` feature, which compiles
+// in the sockaddr_in6 paths and the IPv6 multicast and IPV6_V6ONLY helpers. With either missing,
+// IPv6 addresses are refused with EAFNOSUPPORT.
+const IPV6_ENABLED: bool = cfg!(feature = "ipv6") && AF_INET6 != AF_UNSPEC;
+
+// Socket family for `addr`. EAFNOSUPPORT for an IPv6 address without IPv6 support (see
+// IPV6_ENABLED), rather than a socket() of the wrong family.
+pub fn address_family(addr: &SocketAddr) -> Result<c_int> {
+    match addr {
+        SocketAddr::V4(_) => Ok(AF_INET),
+        SocketAddr::V6(_) if IPV6_ENABLED => Ok(AF_INET6),
+        SocketAddr::V6(_) => Err(Error::from_errno(EAFNOSUPPORT)),
+    }
+}
+
+// Build the sockaddr for bind/connect/sendto from a SocketAddr: a sockaddr_in or sockaddr_in6
+// (port and address in network order, the family from the bindings' AF_INET/AF_INET6, which
+// don't match Linux on every LwIP configuration) in a sockaddr_storage, plus its valid length.
+// An IPv6 address fails with EAFNOSUPPORT without the `ipv6` feature, and also with it when the
+// bindings' sockaddr_storage is too small for a sockaddr_in6 (an IPv4-only LwIP build).
+pub fn socket_addr_to_sockaddr(addr: &SocketAddr) -> Result<(sockaddr_storage, socklen_t)> {
+    let mut storage: sockaddr_storage = unsafe { core::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = sockaddr_in_from(addr);
+            unsafe { write_sockaddr(&mut storage, sin) }
+        }
+        #[cfg(feature = "ipv6")]
+        SocketAddr::V6(addr) => {
+            let sin6 = sockaddr_in6_from(addr);
+            unsafe { write_sockaddr(&mut storage, sin6) }
+        }
+        #[cfg(not(feature = "ipv6"))]
+        SocketAddr::V6(_) => 0,
+    };
+    match len {
+        0 => Err(Error::from_errno(EAFNOSUPPORT)),
+        len => Ok((storage, len as socklen_t)),
+    }
+}
+
+// Copy a family-specific sockaddr into a sockaddr_storage, returning the bytes written (0 if it
+// doesn't fit).
+unsafe fn write_sockaddr<T>(storage: &mut sockaddr_storage, addr: T) -> usize {
+    let len = core::mem::size_of::<T>();
+    if len > core::mem::size_of::<sockaddr_storage>() {
+        return 0;
+    }
+    core::ptr::copy_nonoverlapping(
+        &addr as *const T as *const u8,
+        storage as *mut sockaddr_storage as *mut u8,
+        len,
+    );
+    len
+}
+
+// Decode the address LwIP wrote for accept/recvfrom/getpeername/getsockname back into a
+// SocketAddr, with the port (and IPv6 flowinfo) converted from network order and the IPv6 scope
+// id kept for link-local addresses. None for an unknown family or a length too short for it.
+pub fn sockaddr_to_socket_addr(storage: &sockaddr_storage, len: socklen_t) -> Option<SocketAddr> {
+    let len = (len as usize).min(core::mem::size_of::<sockaddr_storage>());
+    match storage.ss_family as c_int {
+        AF_INET if len >= core::mem::size_of::<sockaddr_in>() => {
+            let sin: sockaddr_in = unsafe { read_sockaddr(storage) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        // Without LWIP_IPV6, AF_INET6 collapses to AF_UNSPEC and must not match.
+        #[cfg(feature = "ipv6")]
+        family
+            if family == AF_INET6
+                && IPV6_ENABLED
+                && len >= core::mem::size_of::<sockaddr_in6>() =>
+        {
+            let sin6: sockaddr_in6 = unsafe { read_sockaddr(storage) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                sin6.sin6_addr.s6_addr.into(),
+                u16::from_be(sin6.sin6_port),
+                u32::from_be(sin6.sin6_flowinfo),
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+// Read a family-specific sockaddr out of a sockaddr_storage. The caller must have checked that
+// T fits (which a length check against the storage size guarantees).
+unsafe fn read_sockaddr<T>(storage: &sockaddr_storage) -> T {
+    debug_assert!(core::mem::size_of::<T>() <= core::mem::size_of::<sockaddr_storage>());
+    core::ptr::read_unaligned(storage as *const sockaddr_storage as *const T)
+}
+
+// Datagram analogue of a vectored send: transmit `bufs` back to back as a single datagram to
+// `addr`, e.g. a protocol header and payload held in separate buffers, without first copying them
+// together. At most IOV_MAX buffers. Returns the bytes sent.
+pub fn send_to_vectored(
+    sock: RawSocket,
+    bufs: &[IoSlice],
+    flags: c_int,
+    addr: SocketAddr,
+) -> Result<usize> {
+    if bufs.len() > IOV_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "more than IOV_MAX buffers",
+        ));
+    }
+    let (mut to, tolen) = socket_addr_to_sockaddr(&addr)?;
+    let mut iov: Vec<iovec> = bufs
+        .iter()
+        .map(|buf| iovec {
+            iov_base: buf.as_ptr() as *mut c_void,
+            iov_len: buf.len().min(i32::MAX as usize) as size_t,
+        })
+        .collect();
+    let message = msghdr {
+        msg_name: &mut to as *mut sockaddr_storage as *mut c_void,
+        msg_namelen: tolen,
+        msg_iov: iov.as_mut_ptr(),
+        msg_iovlen: iov.len() as c_int,
+        msg_control: core::ptr::null_mut(),
+        msg_controllen: 0,
+        msg_flags: 0,
+    };
+    match sendmsg(sock, &message, flags) {
+        n if n >= 0 => Ok(n as usize),
+        _ => Err(Error::last()),
+    }
+}
+
+// recv() into uninitialised memory, avoiding the cost of zeroing a large receive buffer before
+// every call. On Ok(n) the first n bytes of `buf` have been written by LwIP and may be assumed
+// initialised; the rest of `buf` (all of it on Ok(0) or an error) is untouched. This is sound
+// because LwIP only ever writes into the buffer it is given and never reads it.
+pub fn recv_uninit(sock: RawSocket, buf: &mut [MaybeUninit<u8>]) -> Result<usize> {
+    let len = buf.len().min(i32::MAX as usize) as i32;
+    match recv(sock, buf.as_mut_ptr() as *mut c_void, len, 0) {
+        n if n >= 0 => Ok(n as usize),
+        _ => Err(Error::last()),
+    }
+}
+
+// Bind to a local address that need not be assigned to any interface yet, e.g. a service that
+// starts before DHCP has finished - the equivalent of Linux's IP_FREEBIND. LwIP's tcp_bind and
+// udp_bind only check for conflicting pcbs, never whether the address is configured on a netif,
+// so this is a plain bind(); traffic for the address is delivered once it is assigned. This saves
+// services from polling is_netif_initialised() before binding.
+pub fn bind_freebind(sock: RawSocket, addr: SocketAddr) -> Result<()> {
+    let (name, namelen) = socket_addr_to_sockaddr(&addr)?;
+    match bind(
+        sock,
+        &name as *const sockaddr_storage as *const sockaddr,
+        namelen,
+    ) {
+        0 => Ok(()),
+        _ => Err(Error::last()),
+    }
+}
+
+// POSIX-style interface queries for code ported from systems that use the SIOCGIFADDR family of
+// ioctls. LwIP's lwip_ioctl only implements FIONREAD and FIONBIO, so these are answered from the
+// netif list instead: `ifname` is the LwIP netif name (two letters plus number, e.g. "st0").
+// None if there is no such interface or it has no IPv4 address configured.
+pub fn interface_address(ifname: &str) -> Option<Ipv4Addr> {
+    interface_ipv4(ifname).map(|(addr, _)| addr)
+}
+
+pub fn interface_netmask(ifname: &str) -> Option<Ipv4Addr> {
+    interface_ipv4(ifname).map(|(_, netmask)| netmask)
+}
+
+// Directed broadcast address of the interface's subnet (SIOCGIFBRDADDR).
+pub fn interface_broadcast(ifname: &str) -> Option<Ipv4Addr> {
+    interface_ipv4(ifname)
+        .map(|(addr, netmask)| Ipv4Addr::from(u32::from(addr) | !u32::from(netmask)))
+}
+
+fn interface_ipv4(ifname: &str) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    netif_find(ifname)?.with(|netif| {
+        Some((
+            ipv4_from_lwip(netif.ip_addr)?,
+            ipv4_from_lwip(netif.netmask).unwrap_or(Ipv4Addr::UNSPECIFIED),
+        ))
+    })
+}
+
+// Send `buf` as a directed broadcast out of every interface that has an IPv4 address (loopback
+// excepted), so discovery traffic reaches every attached segment rather than only the one behind
+// the default route. `sock` must be a UDP socket with SO_BROADCAST enabled. Each interface's
+// subnet broadcast address is used as the destination, which LwIP routes out of that interface.
+// Interfaces that aren't ipv4_ready are skipped rather than counted as failures. The truncated
+// struct netif in these bindings has no flags, so an interface that was taken down but kept its
+// address can't be told apart and is still attempted.
+// Returns (interfaces sent on, interfaces attempted).
+pub fn broadcast_datagram(sock: RawSocket, buf: &[u8], port: u16) -> (usize, usize) {
+    // Walk the netif list on the tcpip thread, where it can't change underneath us.
+    let collect = || {
+        let mut targets = Vec::new();
+        unsafe {
+            let mut netif = netif_list;
+            while !netif.is_null() {
+                let addr = u32::from_be((*netif).ip_addr.addr);
+                let netmask = u32::from_be((*netif).netmask.addr);
+                if Netif(netif).ipv4_ready() && !Ipv4Addr::from(addr).is_loopback() {
+                    targets.push(Ipv4Addr::from(addr | !netmask));
+                }
+                netif = (*netif).next;
+            }
+        }
+        targets
+    };
+    let targets = tcpip_call(collect).unwrap_or_else(collect);
+
+    let len = buf.len().min(i32::MAX as usize) as i32;
+    let sent = targets
+        .iter()
+        .filter(|&&target| {
+            let to = sockaddr_in_from(&SocketAddrV4::new(target, port));
+            let retval = sendto(
+                sock,
+                buf.as_ptr() as *const c_void,
+                len,
+                0,
+                &to as *const sockaddr_in as *const sockaddr,
+                core::mem::size_of::<sockaddr_in>() as socklen_t,
+            );
+            retval >= 0
+        })
+        .count();
+    (sent, targets.len())
+}
+
+// Enable or disable delivery of IP_PKTINFO ancillary data (destination address and ingress
+// interface) with received datagrams, read back via recvmsg. Needs LWIP_NETBUF_RECVINFO; fails
+// with ENOPROTOOPT when LwIP was built without it. LwIP has no IPV6_RECVPKTINFO, and these
+// bindings are IPv4-only, so the IPv4 option is used regardless of the socket's family.
+pub fn set_recv_pktinfo(sock: RawSocket, enable: bool) -> Result<()> {
+    set_sockopt_int(sock, IPPROTO_IP, IP_PKTINFO, enable as c_int)
+}
+
+// Whether IP_PKTINFO delivery is enabled. Stock LwIP accepts IP_PKTINFO in setsockopt but not
+// getsockopt, so expect ENOPROTOOPT there.
+pub fn recv_pktinfo(sock: RawSocket) -> Result<bool> {
+    get_sockopt_int(sock, IPPROTO_IP, IP_PKTINFO).map(|v| v != 0)
+}
+
+// Wait on several socket operations at once and run the branch for whichever becomes ready first,
+// without hand-assembling a pollfd array. The first arm gives the poll timeout in milliseconds
+// (-1 waits forever) and the expression to evaluate if nothing becomes ready; the second, the
+// expression to evaluate if poll() itself fails (EBADF, ENOMEM, ...), with the crate Error bound to
+// the given pattern. Each following arm is `read(sock)`, `write(sock)` or `accept(sock)` (a
+// listening socket with a pending connection). The arms are checked in order, so an earlier arm
+// wins if several are ready at once. An error or hang-up on a socket counts as ready, so its
+// branch sees the failure on its next call.
+//
+//     let event = lwip_select! {
+//         timeout(500) => Event::Idle,
+//         error(err) => return Err(err),
+//         read(control) => Event::Command,
+//         write(telemetry) => Event::CanSend,
+//         accept(listener) => Event::NewClient,
+//     };
+#[macro_export]
+macro_rules! lwip_select {
+    (@events read) => {
+        $crate::POLLIN
+    };
+    (@events accept) => {
+        $crate::POLLIN
+    };
+    (@events write) => {
+        $crate::POLLOUT
+    };
+    (
+        timeout($ms:expr) => $on_timeout:expr,
+        error($err:pat) => $on_error:expr,
+        $($op:ident($sock:expr) => $body:expr),+ $(,)?
+    ) => {{
+        let mut fds = [$(
+            $crate::pollfd {
+                fd: $sock,
+                events: $crate::lwip_select!(@events $op),
+                revents: 0,
+            },
+        )+];
+        let ready = $crate::poll(fds.as_mut_ptr(), fds.len() as $crate::nfds_t, $ms);
+        let winner = if ready > 0 {
+            fds.iter().position(|fd| fd.revents != 0)
+        } else {
+            None
+        };
+        if ready < 0 {
+            let $err = $crate::Error::last();
+            $on_error
+        } else {
+            'select: {
+                let mut _arm = 0usize;
+                $(
+                    if winner == Some(_arm) {
+                        break 'select ($body);
+                    }
+                    _arm += 1;
+                )+
+                $on_timeout
+            }
+        }
+    }};
+}
+
+// Token-bucket limiter for outbound throughput, clocked by sys_now() so it can't drift from the
+// stack's own idea of time. The bucket refills at `rate` bytes per second up to `burst` bytes.
+// Tokens are kept in byte-milliseconds so slow rates don't lose fractional refills.
+pub struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    blocking: bool,
+    millitokens: u64,
+    last_tick: u32,
+}
+
+impl RateLimiter {
+    // A blocking limiter that starts with a full bucket.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self::new_at(rate, burst, unsafe { sys_now() })
+    }
+
+    fn new_at(rate: u32, burst: u32, now: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst,
+            blocking: true,
+            millitokens: burst as u64 * 1000,
+            last_tick: now,
+        }
+    }
+
+    // When blocking, send_limited sleeps until the bucket refills; otherwise it fails with
+    // WouldBlock.
+    pub fn set_blocking(&mut self, blocking: bool) {
+        self.blocking = blocking;
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    // Bytes that may be sent right now.
+    pub fn available(&mut self) -> u32 {
+        self.available_at(unsafe { sys_now() })
+    }
+
+    fn available_at(&mut self, now: u32) -> u32 {
+        self.refill_at(now);
+        (self.millitokens / 1000) as u32
+    }
+
+    fn refill_at(&mut self, now: u32) {
+        let elapsed = now.wrapping_sub(self.last_tick) as u64;
+        self.last_tick = now;
+        self.millitokens =
+            (self.millitokens + elapsed * self.rate as u64).min(self.burst as u64 * 1000);
+    }
+
+    fn consume(&mut self, bytes: u32) {
+        self.millitokens = self.millitokens.saturating_sub(bytes as u64 * 1000);
+    }
+}
+
+// send() throttled by `limiter`: sends as much of `buf` as the bucket allows (so a short write is
+// normal), and returns the number of bytes sent. When the bucket is empty a blocking limiter
+// sleeps until it holds enough for the smaller of `buf` and the burst size; a non-blocking one
+// fails with WouldBlock, as a non-blocking socket with a full send buffer would. A limiter with a
+// zero rate or burst never refills.
+pub fn send_limited(sock: RawSocket, buf: &[u8], limiter: &mut RateLimiter) -> Result<usize> {
+    let sent = |retval: i32| match retval {
+        n if n >= 0 => Ok(n as usize),
+        _ => Err(Error::last()),
+    };
+    if buf.is_empty() {
+        return sent(send(sock, buf.as_ptr() as *const c_void, 0, 0));
+    }
+    let mut available = limiter.available();
+    if available == 0 {
+        if !limiter.blocking || limiter.rate == 0 || limiter.burst == 0 {
+            return Err(Error::new(ErrorKind::WouldBlock, "send rate limit reached"));
+        }
+        let wanted = (buf.len().min(limiter.burst as usize)) as u64 * 1000;
+        let deficit = wanted.saturating_sub(limiter.millitokens);
+        let wait_ms = deficit.div_ceil(limiter.rate as u64).min(u32::MAX as u64) as u32;
+        unsafe { sys_msleep(wait_ms) };
+        available = limiter.available();
+    }
+    let len = buf.len().min(available as usize).min(i32::MAX as usize) as i32;
+    let retval = send(sock, buf.as_ptr() as *const c_void, len, 0);
+    if retval > 0 {
+        limiter.consume(retval as u32);
+    }
+    sent(retval)
+}
+
+// Connection lifecycle events reported to the logger installed with set_connection_logger.
+// Addresses are None where LwIP couldn't supply them; `err` is the LwIP errno.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected {
+        local: Option<SocketAddr>,
+        peer: Option<SocketAddr>,
+    },
+    Accepted {
+        local: Option<SocketAddr>,
+        peer: Option<SocketAddr>,
+    },
+    Closed {
+        peer: Option<SocketAddr>,
+        graceful: bool,
+    },
+    ConnectFailed {
+        peer: Option<SocketAddr>,
+        err: i32,
+    },
+}
+
+type ConnectionLogger = Arc<dyn Fn(ConnectionEvent) + Send + Sync>;
+
+static CONNECTION_LOGGER: Mutex<Option<ConnectionLogger>> = Mutex::new(None);
+static CONNECTION_LOGGER_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Install a single crate-wide hook that sees every TCP connect, accept and close made through
+// these wrappers, e.g. for security or compliance audit logging. The callback runs synchronously
+// on the task making the socket call, so keep it cheap and don't block in it. It runs without the
+// logger lock held, so it may make socket calls (which are logged in turn) and replace or clear
+// the logger. A non-blocking connect that is still in progress (EINPROGRESS) isn't reported.
+pub fn set_connection_logger(cb: impl Fn(ConnectionEvent) + Send + Sync + 'static) {
+    *CONNECTION_LOGGER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(cb));
+    CONNECTION_LOGGER_ACTIVE.store(true, Ordering::Relaxed);
+}
+
+pub fn clear_connection_logger() {
+    CONNECTION_LOGGER_ACTIVE.store(false, Ordering::Relaxed);
+    *CONNECTION_LOGGER.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+fn emit_connection_event(event: ConnectionEvent) {
+    let cb = CONNECTION_LOGGER
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some(cb) = cb {
+        cb(event);
+    }
+}
+
+// `err` is the connect's errno, or 0 if it succeeded.
+fn log_connect(sock: RawSocket, name: *const sockaddr, namelen: socklen_t, err: i32) {
+    if getsockopt_int(sock, SOL_SOCKET, SO_TYPE) != Some(SOCK_STREAM) {
+        return;
+    }
+    let peer = if name.is_null() {
+        None
+    } else {
+        let mut storage: sockaddr_storage = unsafe { core::mem::zeroed() };
+        let len = (namelen as usize).min(core::mem::size_of::<sockaddr_storage>());
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                name as *const u8,
+                &mut storage as *mut sockaddr_storage as *mut u8,
+                len,
+            )
+        };
+        sockaddr_to_socket_addr(&storage, len as socklen_t)
+    };
+    if err == 0 {
+        emit_connection_event(ConnectionEvent::Connected {
+            local: sockaddr_of(sock, getsockname),
+            peer,
+        });
+    } else if err != EINPROGRESS {
+        emit_connection_event(ConnectionEvent::ConnectFailed { peer, err });
+    }
+}
+
+// Fetch a socket's local or peer address with getsockname/getpeername.
+fn sockaddr_of(
+    sock: RawSocket,
+    query: fn(RawSocket, *mut sockaddr, *mut socklen_t) -> c_int,
+) -> Option<SocketAddr> {
+    let mut addr: sockaddr_storage = unsafe { core::mem::zeroed() };
+    let mut len = core::mem::size_of::<sockaddr_storage>() as socklen_t;
+    match query(
+        sock,
+        &mut addr as *mut sockaddr_storage as *mut sockaddr,
+        &mut len,
+    ) {
+        0 => sockaddr_to_socket_addr(&addr, len),
+        _ => None,
+    }
+}
+
+// The tcpip thread's task handle, so tcpip_call can tell it is already on that thread (from a
+// netif status callback, say) and run `f` directly instead of waiting on itself. Recorded at
+// start-up by tcpip_init or lwip_rs_tcpip_init_done, and otherwise only once the thread runs one
+// of our callbacks: a tcpip-thread caller before that (a status callback fired during netif_add,
+// on a port that skipped both) isn't recognised and deadlocks.
+static TCPIP_TASK: AtomicPtr<c_void> = AtomicPtr::new(core::ptr::null_mut());
+
+type TcpipInitDone = Box<dyn FnOnce() + Send>;
+
+// Start LwIP's tcpip thread (tcpip_init) and run `done` on it once the stack is up. Use this
+// rather than calling tcpip_init directly: it records the tcpip thread first, so tcpip_call -
+// behind the Netif accessors, dhcp_state, set_static_ipv4, start_dhcp and stop_dhcp - is safe on
+// that thread from the start.
+pub fn tcpip_init(done: impl FnOnce() + Send + 'static) {
+    let done: Box<TcpipInitDone> = Box::new(Box::new(done));
+    unsafe {
+        lwip_tcpip_init(
+            Some(run_tcpip_init_done),
+            Box::into_raw(done) as *mut c_void,
+        )
+    };
+}
+
+extern "C" fn run_tcpip_init_done(ctx: *mut c_void) {
+    note_tcpip_task();
+    let done = unsafe { Box::from_raw(ctx as *mut TcpipInitDone) };
+    done();
+}
+
+// For ports that start LwIP from C: pass this to tcpip_init as its done callback (the argument is
+// ignored), or call it from the port's own, to record the tcpip thread as tcpip_init above does.
+#[no_mangle]
+pub extern "C" fn lwip_rs_tcpip_init_done(_arg: *mut c_void) {
+    note_tcpip_task();
+}
+
+fn on_tcpip_thread() -> bool {
+    let task = TCPIP_TASK.load(Ordering::Relaxed);
+    !task.is_null() && task == unsafe { xTaskGetCurrentTaskHandle() }
+}
+
+fn note_tcpip_task() {
+    TCPIP_TASK.store(unsafe { xTaskGetCurrentTaskHandle() }, Ordering::Relaxed);
+}
+
+// Run `f` with the LwIP core to itself and wait for its result, for the LwIP calls that aren't
+// safe to make from application tasks. With the `tcpip-core-locking` feature this takes the core
+// lock on the calling task; otherwise `f` is queued to the tcpip thread (tcpip_callback), which
+// costs an allocation and a channel per call. None if the callback couldn't be queued (out of
+// memory). Needs tcpip_init to have run.
+fn tcpip_call<R, F>(f: F) -> Option<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    if on_tcpip_thread() {
+        return Some(f());
+    }
+    #[cfg(feature = "tcpip-core-locking")]
+    {
+        // Locked calls never run on the tcpip thread, so have it record itself once (see
+        // on_tcpip_thread); retried on the next call if this can't be queued.
+        static NOTED: AtomicBool = AtomicBool::new(false);
+        if !NOTED.load(Ordering::Relaxed)
+            && unsafe { tcpip_callback(run_note_tcpip_task, core::ptr::null_mut()) } == 0
+        {
+            NOTED.store(true, Ordering::Relaxed);
+        }
+        // Older toolchains still want unsafe around addr_of_mut! on an extern static.
+        #[allow(unused_unsafe)]
+        let lock = unsafe { core::ptr::addr_of_mut!(lock_tcpip_core) } as *mut c_void;
+        unsafe { sys_mutex_lock(lock) };
+        let retval = f();
+        unsafe { sys_mutex_unlock(lock) };
+        Some(retval)
+    }
+    #[cfg(not(feature = "tcpip-core-locking"))]
+    tcpip_queue(f)
+}
+
+#[cfg(not(feature = "tcpip-core-locking"))]
+type TcpipJob = Box<dyn FnOnce() + Send>;
+
+#[cfg(not(feature = "tcpip-core-locking"))]
+fn tcpip_queue<R, F>(f: F) -> Option<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::sync_channel(1);
+    let job: Box<TcpipJob> = Box::new(Box::new(move || {
+        let _ = tx.send(f());
+    }));
+    let ctx = Box::into_raw(job) as *mut c_void;
+    if unsafe { tcpip_callback(run_tcpip_job, ctx) } != 0 {
+        drop(unsafe { Box::from_raw(ctx as *mut TcpipJob) });
+        return None;
+    }
+    rx.recv().ok()
+}
+
+#[cfg(feature = "tcpip-core-locking")]
+extern "C" fn run_note_tcpip_task(_ctx: *mut c_void) {
+    note_tcpip_task();
+}
+
+#[cfg(not(feature = "tcpip-core-locking"))]
+extern "C" fn run_tcpip_job(ctx: *mut c_void) {
+    note_tcpip_task();
+    let job = unsafe { Box::from_raw(ctx as *mut TcpipJob) };
+    job();
+}
+
+// Whether the default interface's gateway answers, as opposed to merely having an address, so a
+// device can spot "connected but no upstream" and fail over. Probes with ARP: true as soon as the
+// gateway is in the ARP table, sending ARP requests until `timeout` otherwise. An entry LwIP still
+// holds from recent traffic counts as reachable. Ok(false) if there is no gateway or it doesn't
+// answer in time; ENOMEM if a probe couldn't be queued to the tcpip thread, and EOPNOTSUPP without
+// the `arp` feature. Blocks the calling task; the ARP calls themselves run on the tcpip thread.
+#[cfg(feature = "arp")]
+pub fn gateway_reachable(timeout: Duration) -> Result<bool> {
+    const PROBE_INTERVAL_MS: u32 = 100;
+
+    let gateway = Netif::default_interface().with(|netif| netif.gw);
+    if gateway.addr == 0 {
+        return Ok(false);
+    }
+    let start = unsafe { sys_now() };
+    let limit = timeout.as_millis().min(u32::MAX as u128) as u32;
+    loop {
+        let found = tcpip_call(move || unsafe {
+            let netif = core::ptr::addr_of!(gnetif) as *mut netif;
+            let mut eth_ret: *mut c_void = core::ptr::null_mut();
+            let mut ip_ret: *const ip4_addr_t = core::ptr::null();
+            if etharp_find_addr(netif, &gateway, &mut eth_ret, &mut ip_ret) >= 0 {
+                return true;
+            }
+            etharp_request(netif, &gateway);
+            false
+        })
+        .ok_or_else(|| Error::from_errno(ENOMEM))?;
+        if found {
+            return Ok(true);
+        }
+        let elapsed = unsafe { sys_now() }.wrapping_sub(start);
+        if elapsed >= limit {
+            return Ok(false);
+        }
+        unsafe { sys_msleep(PROBE_INTERVAL_MS.min(limit - elapsed)) };
+    }
+}
+
+#[cfg(not(feature = "arp"))]
+pub fn gateway_reachable(_timeout: Duration) -> Result<bool> {
+    Err(Error::from_errno(EOPNOTSUPP))
+}
+
+// Operator-pinned addressing for the default interface: stop its DHCP client and assign `addr`,
+// `netmask` and `gateway` in one step. Read back with netif_ipv4_addr/netif_netmask/
+// netif_gateway; dhcp_state() then reports Off. Like start_dhcp and stop_dhcp this changes the
+// shared gnetif, which LwIP only allows from the tcpip thread, so the work is queued there and the
+// caller blocks until it is done. On the tcpip thread itself they run directly, but only once the
+// thread has been recorded (see tcpip_init); before that they deadlock there.
+pub fn set_static_ipv4(addr: Ipv4Addr, netmask: Ipv4Addr, gateway: Ipv4Addr) -> Result<()> {
+    let lwip_addr = |a: Ipv4Addr| ip4_addr_t {
+        addr: u32::from(a).to_be(),
+    };
+    let (addr, netmask, gateway) = (lwip_addr(addr), lwip_addr(netmask), lwip_addr(gateway));
+    tcpip_call(move || unsafe {
+        let netif = core::ptr::addr_of!(gnetif) as *mut netif;
+        dhcp_stop(netif);
+        netif_set_addr(netif, &addr, &netmask, &gateway);
+    })
+    .ok_or_else(|| Error::from_errno(ENOMEM))?;
+    DEFAULT_DHCP.store(DHCP_STOPPED, Ordering::Relaxed);
+    Ok(())
+}
+
+// (Re)start the DHCP client on the default interface; the address arrives asynchronously
+// (wait_for_netif, dhcp_state). See set_static_ipv4 for calling it on the tcpip thread.
+pub fn start_dhcp() -> Result<()> {
+    let err = tcpip_call(|| unsafe { dhcp_start(core::ptr::addr_of!(gnetif) as *mut netif) })
+        .ok_or_else(|| Error::from_errno(ENOMEM))?;
+    match err {
+        0 => {
+            DEFAULT_DHCP.store(DHCP_STARTED, Ordering::Relaxed);
+            Ok(())
+        }
+        err => Err(Error::from_errno(unsafe { err_to_errno(err) })),
+    }
+}
+
+// Stop the DHCP client on the default interface. LwIP 2.1 and later also release the lease and
+// clear the address; 2.0 leaves the address in place. Either way dhcp_state reports Off after.
+// See set_static_ipv4 for calling it on the tcpip thread.
+pub fn stop_dhcp() -> Result<()> {
+    tcpip_call(|| unsafe { dhcp_stop(core::ptr::addr_of!(gnetif) as *mut netif) })
+        .ok_or_else(|| Error::from_errno(ENOMEM))?;
+    DEFAULT_DHCP.store(DHCP_STOPPED, Ordering::Relaxed);
+    Ok(())
+}
+
+// Upper bound on ARP_TABLE_SIZE, which isn't in the bindings: LwIP refuses to build with a table
+// that doesn't fit an s8_t index. etharp_get_entry bounds-checks the index itself, but reports an
+// out-of-range index and an empty slot alike, so arp_entries scans this far instead of stopping
+// at the first miss.
+#[cfg(feature = "arp")]
+const ARP_TABLE_SIZE_MAX: size_t = 0x7f;
+
+// The stable entries of LwIP's ARP cache as (IPv4 address, MAC) pairs, for debugging. Empty and
+// pending slots are skipped. The table is read on the tcpip thread that owns it, but entries can
+// be added or expire the moment it is released, so treat the result as a snapshot that may
+// already be stale. Empty if the call couldn't be queued, or without the `arp` feature.
+#[cfg(feature = "arp")]
+pub fn arp_entries() -> Vec<(Ipv4Addr, [u8; 6])> {
+    tcpip_call(|| {
+        let mut entries = Vec::new();
+        for i in 0..ARP_TABLE_SIZE_MAX {
+            let mut ipaddr: *mut ip4_addr_t = core::ptr::null_mut();
+            let mut netif: *mut netif = core::ptr::null_mut();
+            let mut eth: *mut c_void = core::ptr::null_mut();
+            let stable = unsafe { etharp_get_entry(i, &mut ipaddr, &mut netif, &mut eth) };
+            if stable != 1 || ipaddr.is_null() || eth.is_null() {
+                continue;
+            }
+            // struct eth_addr is just the six address bytes.
+            let mac = unsafe { *(eth as *const [u8; 6]) };
+            let addr = Ipv4Addr::from(u32::from_be(unsafe { (*ipaddr).addr }));
+            entries.push((addr, mac));
+        }
+        entries
+    })
+    .unwrap_or_default()
+}
+
+#[cfg(not(feature = "arp"))]
+pub fn arp_entries() -> Vec<(Ipv4Addr, [u8; 6])> {
+    Vec::new()
+}
+
+struct RecvWatermark {
+    sock: RawSocket,
+    bytes: usize,
+    cb: Arc<dyn Fn() + Send + Sync>,
+    crossed: bool,
+}
+
+// As with the priority boosts, the flag lets close() skip the lock when no watermarks are set.
+static RECV_WATERMARKS: Mutex<Vec<RecvWatermark>> = Mutex::new(Vec::new());
+static RECV_WATERMARKS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Arrange for `cb` to be called once at least `bytes` are queued for reading on `sock`, so an
+// application can wait for a whole message before processing instead of churning on partial
+// reads. LwIP accepts SO_RCVLOWAT as an option name but doesn't implement it, so this is emulated:
+// call check_recv_watermarks() from the poll loop and it compares FIONREAD against each
+// watermark. The callback fires once per crossing and re-arms after the queue drops below the
+// watermark again. Replaces any watermark already set on `sock`. FIONREAD needs LWIP_SO_RCVBUF.
+pub fn set_recv_watermark(sock: RawSocket, bytes: usize, cb: impl Fn() + Send + Sync + 'static) {
+    let mut watermarks = RECV_WATERMARKS.lock().unwrap_or_else(|e| e.into_inner());
+    watermarks.retain(|w| w.sock != sock);
+    watermarks.push(RecvWatermark {
+        sock,
+        bytes,
+        cb: Arc::new(cb),
+        crossed: false,
+    });
+    RECV_WATERMARKS_ACTIVE.store(true, Ordering::Relaxed);
+}
+
+pub fn clear_recv_watermark(sock: RawSocket) {
+    let mut watermarks = RECV_WATERMARKS.lock().unwrap_or_else(|e| e.into_inner());
+    watermarks.retain(|w| w.sock != sock);
+    RECV_WATERMARKS_ACTIVE.store(!watermarks.is_empty(), Ordering::Relaxed);
+}
+
+// Fire the callbacks of every watermark crossed since the last check; returns how many fired.
+// Callbacks run on the calling task after the watermark lock is released, so they are free to
+// close sockets or set and clear watermarks.
+pub fn check_recv_watermarks() -> usize {
+    let mut fired = Vec::new();
+    let mut watermarks = RECV_WATERMARKS.lock().unwrap_or_else(|e| e.into_inner());
+    for watermark in watermarks.iter_mut() {
+        let mut queued: c_int = 0;
+        let retval = ioctl(
+            watermark.sock,
+            FIONREAD,
+            &mut queued as *mut c_int as *mut c_void,
+        );
+        if retval != 0 {
+            continue;
+        }
+        let above = queued.max(0) as usize >= watermark.bytes;
+        if above && !watermark.crossed {
+            fired.push(Arc::clone(&watermark.cb));
+        }
+        watermark.crossed = above;
+    }
+    drop(watermarks);
+    for cb in &fired {
+        cb();
+    }
+    fired.len()
+}
+
+// msg_flags reported by recvmsg: MSG_TRUNC (datagram longer than the buffers, excess dropped)
+// and MSG_CTRUNC (control buffer too small, some ancillary data dropped).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecvMsgFlags(pub c_int);
+
+impl RecvMsgFlags {
+    pub fn truncated(self) -> bool {
+        self.0 & MSG_TRUNC != 0
+    }
+
+    pub fn control_truncated(self) -> bool {
+        self.0 & MSG_CTRUNC != 0
+    }
+}
+
+// Destination address and ingress interface of a datagram, from IP_PKTINFO ancillary data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub ifindex: u32,
+    pub dst: Ipv4Addr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvMsgResult {
+    pub bytes: usize,
+    pub flags: RecvMsgFlags,
+    pub source: Option<SocketAddr>,
+    pub pktinfo: Option<PacketInfo>,
+    pub control_len: usize,
+}
+
+// LwIP's struct cmsghdr and struct in_pktinfo, not in the bindings.
+#[repr(C)]
+struct cmsghdr {
+    cmsg_len: socklen_t,
+    cmsg_level: c_int,
+    cmsg_type: c_int,
+}
+
+#[repr(C)]
+struct in_pktinfo {
+    ipi_ifindex: core::ffi::c_uint,
+    ipi_addr: in_addr,
+}
+
+// LwIP aligns control messages and their data to sizeof(long) (ALIGN_H/ALIGN_D).
+const fn cmsg_align(len: usize) -> usize {
+    let align = core::mem::size_of::<core::ffi::c_long>();
+    (len + align - 1) & !(align - 1)
+}
+
+// recvmsg() with everything LwIP reports made available: the byte count, msg_flags (so a caller
+// using pktinfo or other ancillary data can tell the control buffer was too small), the source
+// address and any IP_PKTINFO control message. The raw recvmsg wrapper leaves msg_flags for the
+// caller to dig out. At most IOV_MAX buffers.
+pub fn recv_msg_full(
+    sock: RawSocket,
+    bufs: &mut [IoSliceMut],
+    control: &mut [u8],
+) -> Result<RecvMsgResult> {
+    if bufs.len() > IOV_MAX {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "more than IOV_MAX buffers",
+        ));
+    }
+    let mut from: sockaddr_storage = unsafe { core::mem::zeroed() };
+    let mut iov: Vec<iovec> = bufs
+        .iter_mut()
+        .map(|buf| iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len().min(i32::MAX as usize) as size_t,
+        })
+        .collect();
+    let mut message = msghdr {
+        msg_name: &mut from as *mut sockaddr_storage as *mut c_void,
+        msg_namelen: core::mem::size_of::<sockaddr_storage>() as socklen_t,
+        msg_iov: iov.as_mut_ptr(),
+        msg_iovlen: iov.len() as c_int,
+        msg_control: control.as_mut_ptr() as *mut c_void,
+        msg_controllen: control.len() as socklen_t,
+        msg_flags: 0,
+    };
+    let retval = recvmsg(sock, &mut message, 0);
+    if retval < 0 {
+        return Err(Error::last());
+    }
+
+    let source = sockaddr_to_socket_addr(&from, message.msg_namelen);
+
+    let control_len = (message.msg_controllen as usize).min(control.len());
+    let mut pktinfo = None;
+    let header_len = cmsg_align(core::mem::size_of::<cmsghdr>());
+    let mut offset = 0;
+    while offset + header_len <= control_len {
+        let header =
+            unsafe { core::ptr::read_unaligned(control.as_ptr().add(offset) as *const cmsghdr) };
+        let len = header.cmsg_len as usize;
+        if len < header_len || offset + len > control_len {
+            break;
+        }
+        if header.cmsg_level == IPPROTO_IP
+            && header.cmsg_type == IP_PKTINFO
+            && len >= header_len + core::mem::size_of::<in_pktinfo>()
+        {
+            let info = unsafe {
+                core::ptr::read_unaligned(
+                    control.as_ptr().add(offset + header_len) as *const in_pktinfo
+                )
+            };
+            pktinfo = Some(PacketInfo {
+                ifindex: info.ipi_ifindex,
+                dst: Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)),
+            });
+        }
+        offset += cmsg_align(len);
+    }
+
+    Ok(RecvMsgResult {
+        bytes: retval as usize,
+        flags: RecvMsgFlags(message.msg_flags),
+        source,
+        pktinfo,
+        control_len,
+    })
+}
+
+// SO_LINGER on with a zero timeout makes close() reset the connection instead of shutting it down.
+fn lingers_abortively(sock: RawSocket) -> bool {
+    matches!(linger(sock), Ok(Some(Duration::ZERO)))
+}
+
+// Typed stand-ins for the family/type/protocol integers socket() takes. Note that Inet6 maps to
+// the bindings' AF_INET6, which is AF_UNSPEC on a build without IPv6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Inet,
+    Inet6,
+    Unspec,
+}
+
+impl AddressFamily {
+    pub fn to_raw(self) -> c_int {
+        match self {
+            AddressFamily::Inet => AF_INET,
+            AddressFamily::Inet6 => AF_INET6,
+            AddressFamily::Unspec => AF_UNSPEC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    Stream,
+    Dgram,
+    Raw,
+}
+
+impl SocketType {
+    pub fn to_raw(self) -> c_int {
+        match self {
+            SocketType::Stream => SOCK_STREAM,
+            SocketType::Dgram => SOCK_DGRAM,
+            SocketType::Raw => SOCK_RAW,
+        }
+    }
+}
+
+// Raw carries any other IP protocol number, e.g. for a SOCK_RAW socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Raw(i32),
+}
+
+impl Protocol {
+    pub fn to_raw(self) -> c_int {
+        match self {
+            Protocol::Tcp => IPPROTO_TCP,
+            Protocol::Udp => IPPROTO_UDP,
+            Protocol::Icmp => IPPROTO_ICMP,
+            Protocol::Raw(proto) => proto,
+        }
+    }
+}
+
+pub fn socket_typed(family: AddressFamily, ty: SocketType, proto: Protocol) -> Result<Socket> {
+    Socket::new(family.to_raw(), ty.to_raw(), proto.to_raw())
+}
+
+// Bookkeeping behind enable_socket_registry: every Socket opened while the registry is enabled
+// gets a non-zero id mapping to its descriptor, removed again just before Drop closes it. An
+// entry's descriptor is therefore always still open and owned by that Socket.
+struct SocketRegistry {
+    next_id: u32,
+    open: BTreeMap<u32, RawSocket>,
+}
+
+impl SocketRegistry {
+    const fn new() -> SocketRegistry {
+        SocketRegistry {
+            next_id: 1,
+            open: BTreeMap::new(),
+        }
+    }
+
+    fn register(&mut self, sock: RawSocket) -> u32 {
+        // 0 marks an unregistered Socket, and an id still in use must not be handed out twice.
+        while self.next_id == 0 || self.open.contains_key(&self.next_id) {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.open.insert(id, sock);
+        id
+    }
+
+    fn deregister(&mut self, id: u32) -> Option<RawSocket> {
+        self.open.remove(&id)
+    }
+
+    fn open_sockets(&self) -> Vec<RawSocket> {
+        self.open.values().copied().collect()
+    }
+}
+
+static SOCKET_REGISTRY: Mutex<SocketRegistry> = Mutex::new(SocketRegistry::new());
+static SOCKET_REGISTRY_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+fn socket_registry() -> std::sync::MutexGuard<'static, SocketRegistry> {
+    SOCKET_REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn register_socket(sock: RawSocket) -> u32 {
+    if !SOCKET_REGISTRY_ACTIVE.load(Ordering::Relaxed) {
+        return 0;
+    }
+    socket_registry().register(sock)
+}
+
+// Opt-in: track every Socket (and so every TcpStream/TcpListener/UdpSocket) opened from now on,
+// so shutdown_all can shut them all down. Costs a lock and a map insert per open and close, which is
+// why it is off by default. Sockets opened before this call, and raw descriptors that never
+// became a Socket, are not tracked.
+pub fn enable_socket_registry() {
+    SOCKET_REGISTRY_ACTIVE.store(true, Ordering::Relaxed);
+}
+
+// Gracefully shut down every connected socket in the registry, e.g. before a reboot or firmware
+// update so peers see a FIN instead of a connection that silently goes dead. Each one gets a
+// write-side shutdown, then its input is drained and discarded until the peer closes too or
+// `timeout` runs out, and finally a full shutdown so later reads and writes on it fail. Returns
+// how many sockets were shut down.
+// Nothing is closed here: each descriptor stays owned by its Socket (or TcpStream, ...) and is
+// closed when that is dropped, so no handle can end up pointing at a reused descriptor. The
+// registry stays locked throughout, so tasks opening or dropping sockets meanwhile wait for it.
+pub fn shutdown_all(timeout: Duration) -> usize {
+    let registry = socket_registry();
+    let socks: Vec<RawSocket> = registry
+        .open_sockets()
+        .into_iter()
+        .filter(|&sock| peer_addr(sock).is_ok() && shutdown(sock, SHUT_WR) == 0)
+        .collect();
+    let mut lingering = socks.clone();
+
+    let start = unsafe { sys_now() };
+    let limit = timeout.as_millis().min(u32::MAX as u128) as u32;
+    while !lingering.is_empty() {
+        let elapsed = unsafe { sys_now() }.wrapping_sub(start);
+        if elapsed >= limit {
+            break;
+        }
+        let mut fds: Vec<pollfd> = lingering
+            .iter()
+            .map(|&sock| pollfd {
+                fd: sock,
+                events: POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let wait = (limit - elapsed).min(c_int::MAX as u32) as c_int;
+        if poll(fds.as_mut_ptr(), fds.len() as nfds_t, wait) <= 0 {
+            break;
+        }
+        // Done with a socket once the peer has closed its side or it has failed outright.
+        lingering.retain(|&sock| !matches!(drain_ready(sock, |_| {}), Ok(d) if !d.eof));
+    }
+
+    for &sock in &socks {
+        shutdown(sock, SHUT_RDWR);
+    }
+    drop(registry);
+    socks.len()
+}
+
+// An owned LwIP socket descriptor, closed when dropped so netconn slots can't leak. Not Clone:
+// exactly one Socket owns a descriptor. The base for the TCP/UDP wrappers. The second field is
+// the socket's id in the registry, 0 if it isn't registered.
+#[derive(Debug)]
+pub struct Socket(RawSocket, u32);
+
+impl Socket {
+    pub fn new(family: c_int, socket_type: c_int, protocol: c_int) -> Result<Socket> {
+        socket_checked(family, socket_type, protocol)
+            .map(|sock| Socket(sock, register_socket(sock)))
+    }
+
+    // A socket of the family `addr` will be used with (see address_family).
+    pub fn for_addr(addr: &SocketAddr, socket_type: c_int, protocol: c_int) -> Result<Socket> {
+        Socket::new(address_family(addr)?, socket_type, protocol)
+    }
+
+    /// Take ownership of a descriptor from socket()/accept().
+    ///
+    /// # Safety
+    ///
+    /// `sock` must be an open descriptor that nothing else will close.
+    pub unsafe fn from_raw(sock: RawSocket) -> Socket {
+        Socket(sock, register_socket(sock))
+    }
+
+    pub fn as_raw(&self) -> RawSocket {
+        self.0
+    }
+
+    // Give up ownership without closing; the caller becomes responsible for close().
+    pub fn into_raw(self) -> RawSocket {
+        let sock = self.0;
+        if self.1 != 0 {
+            socket_registry().deregister(self.1);
+        }
+        core::mem::forget(self);
+        sock
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        if self.1 != 0 {
+            socket_registry().deregister(self.1);
+        }
+        close(self.0);
+    }
+}
+
+// LwIP handed back an address in a family sockaddr_to_socket_addr can't decode.
+fn unsupported_family() -> Error {
+    Error::new(ErrorKind::InvalidData, "unsupported address family")
+}
+
+// getsockname/getpeername decoded to a SocketAddr. The raw wrappers pass LwIP's return value
+// through as-is, so besides the usual -1 with errno, a positive return is taken as the error code
+// itself.
+fn query_socket_addr(
+    sock: RawSocket,
+    query: fn(RawSocket, *mut sockaddr, *mut socklen_t) -> c_int,
+) -> Result<SocketAddr> {
+    let mut addr: sockaddr_storage = unsafe { core::mem::zeroed() };
+    let mut len = core::mem::size_of::<sockaddr_storage>() as socklen_t;
+    let retval = query(
+        sock,
+        &mut addr as *mut sockaddr_storage as *mut sockaddr,
+        &mut len,
+    );
+    match retval {
+        0 => {}
+        code if code > 0 => return Err(Error::from_errno(code)),
+        _ => return Err(Error::last()),
+    }
+    sockaddr_to_socket_addr(&addr, len).ok_or_else(unsupported_family)
+}
+
+// The address the socket is bound to, e.g. to learn the ephemeral port after binding to port 0.
+pub fn local_addr(sock: RawSocket) -> Result<SocketAddr> {
+    query_socket_addr(sock, getsockname)
+}
+
+// The connected peer's address; NotConnected (ENOTCONN) if the socket isn't connected.
+pub fn peer_addr(sock: RawSocket) -> Result<SocketAddr> {
+    query_socket_addr(sock, getpeername)
+}
+
+// A connected TCP socket, usable wherever std::io::Read/Write are expected (with the `std`
+// feature).
+#[derive(Debug)]
+pub struct TcpStream {
+    inner: Socket,
+}
+
+impl TcpStream {
+    // Try each resolved address in turn, returning the first connection made or the last error.
+    pub fn connect<A: ToLwipAddrs>(addr: A) -> Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addr.to_lwip_addrs()? {
+            match TcpStream::connect_addr(addr) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    fn connect_addr(addr: SocketAddr) -> Result<TcpStream> {
+        let inner = Socket::for_addr(&addr, SOCK_STREAM, IPPROTO_TCP)?;
+        let (name, namelen) = socket_addr_to_sockaddr(&addr)?;
+        let retval = connect(
+            inner.as_raw(),
+            &name as *const sockaddr_storage as *const sockaddr,
+            namelen,
+        );
+        if retval != 0 {
+            return Err(Error::last());
+        }
+        Ok(TcpStream { inner })
+    }
+
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        peer_addr(self.inner.as_raw())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        local_addr(self.inner.as_raw())
+    }
+
+    pub fn shutdown(&self, how: ShutdownMode) -> Result<()> {
+        shutdown_socket(self.inner.as_raw(), how)
+    }
+
+    pub fn peek(&self, buf: &mut [u8]) -> Result<usize> {
+        peek(self.inner.as_raw(), buf)
+    }
+
+    pub fn take_error(&self) -> Result<Option<Error>> {
+        take_error(self.inner.as_raw())
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        set_ttl(self.inner.as_raw(), ttl)
+    }
+
+    pub fn ttl(&self) -> Result<u32> {
+        ttl(self.inner.as_raw())
+    }
+
+    pub fn set_linger(&self, linger: Option<Duration>) -> Result<()> {
+        set_linger(self.inner.as_raw(), linger)
+    }
+
+    pub fn linger(&self) -> Result<Option<Duration>> {
+        linger(self.inner.as_raw())
+    }
+
+    // In non-blocking mode Read/Write fail with io::ErrorKind::WouldBlock instead of waiting,
+    // which is what TLS libraries driving a non-blocking handshake expect.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        set_nonblocking(self.inner.as_raw(), nonblocking)
+    }
+
+    pub fn as_raw(&self) -> RawSocket {
+        self.inner.as_raw()
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(recv_slice_retry(self.inner.as_raw(), buf, 0)?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(send_slice_retry(self.inner.as_raw(), buf, 0)?)
+    }
+
+    // LwIP queues written data for transmission itself; there is nothing to flush.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// A byte stream a TLS backend can run over and hand back: the plain TcpStream going in, the
+// secured stream coming out.
+#[cfg(feature = "std")]
+pub trait ReadWrite: std::io::Read + std::io::Write + Send {}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read + std::io::Write + Send> ReadWrite for T {}
+
+// TcpStream must stay usable as a TLS transport.
+#[cfg(feature = "std")]
+const _: fn() = || {
+    fn transport<T: ReadWrite>() {}
+    transport::<TcpStream>();
+};
+
+// The seam for plugging a TLS library (mbedtls, rustls, ...) in over LwIP sockets without this
+// crate depending on one. `handshake` runs the client side of the handshake for server name
+// `domain` over `stream` and returns the secured stream. Errors from `stream` - including
+// WouldBlock if the caller made the socket non-blocking - come through as std::io::Error, which
+// is what the TLS libraries' own IO adapters expect.
+#[cfg(feature = "std")]
+pub trait TlsBackend {
+    fn handshake(
+        &self,
+        stream: Box<dyn ReadWrite>,
+        domain: &str,
+    ) -> std::io::Result<Box<dyn ReadWrite>>;
+}
+
+// TcpStream::connect followed by `backend`'s handshake for `domain`, with the socket left at its
+// defaults. Use connect_tls_with to set non-blocking mode, timeouts or nodelay first.
+#[cfg(feature = "std")]
+pub fn connect_tls<A: ToLwipAddrs>(
+    addr: A,
+    domain: &str,
+    backend: &dyn TlsBackend,
+) -> std::io::Result<Box<dyn ReadWrite>> {
+    connect_tls_with(TcpStream::connect(addr)?, domain, backend)
+}
+
+// `backend`'s handshake for `domain` over a stream the caller has already connected and
+// configured, usually a TcpStream. On a non-blocking stream the handshake can fail with
+// io::ErrorKind::WouldBlock.
+#[cfg(feature = "std")]
+pub fn connect_tls_with(
+    stream: impl ReadWrite + 'static,
+    domain: &str,
+    backend: &dyn TlsBackend,
+) -> std::io::Result<Box<dyn ReadWrite>> {
+    backend.handshake(Box::new(stream), domain)
+}
+
+// A UDP socket modelled on std::net::UdpSocket.
+#[derive(Debug)]
+pub struct UdpSocket {
+    inner: Socket,
+}
+
+impl UdpSocket {
+    pub fn bind(addr: SocketAddr) -> Result<UdpSocket> {
+        let inner = Socket::for_addr(&addr, SOCK_DGRAM, IPPROTO_UDP)?;
+        let (name, namelen) = socket_addr_to_sockaddr(&addr)?;
+        let retval = bind(
+            inner.as_raw(),
+            &name as *const sockaddr_storage as *const sockaddr,
+            namelen,
+        );
+        if retval != 0 {
+            return Err(Error::last());
+        }
+        Ok(UdpSocket { inner })
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        let (to, tolen) = socket_addr_to_sockaddr(&addr)?;
+        let len = buf.len().min(i32::MAX as usize) as i32;
+        let retval = sendto(
+            self.inner.as_raw(),
+            buf.as_ptr() as *const c_void,
+            len,
+            0,
+            &to as *const sockaddr_storage as *const sockaddr,
+            tolen,
+        );
+        match retval {
+            n if n < 0 => Err(Error::last()),
+            n => Ok(n as usize),
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut from: sockaddr_storage = unsafe { core::mem::zeroed() };
+        let len = buf.len().min(i32::MAX as usize) as i32;
+        let (retval, fromlen) = recvfrom_storage(
+            self.inner.as_raw(),
+            buf.as_mut_ptr() as *mut c_void,
+            len,
+            0,
+            &mut from,
+        );
+        if retval < 0 {
+            return Err(Error::last());
+        }
+        let addr = sockaddr_to_socket_addr(&from, fromlen).ok_or_else(unsupported_family)?;
+        Ok((retval as usize, addr))
+    }
+
+    // Fix the peer for send() and recv(); datagrams from other addresses are then filtered out.
+    pub fn connect(&self, addr: SocketAddr) -> Result<()> {
+        let (name, namelen) = socket_addr_to_sockaddr(&addr)?;
+        let retval = connect(
+            self.inner.as_raw(),
+            &name as *const sockaddr_storage as *const sockaddr,
+            namelen,
+        );
+        match retval {
+            0 => Ok(()),
+            _ => Err(Error::last()),
+        }
+    }
+
+    pub fn send(&self, buf: &[u8]) -> Result<usize> {
+        send_slice(self.inner.as_raw(), buf, 0)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        recv_slice(self.inner.as_raw(), buf, 0)
+    }
+
+    pub fn take_error(&self) -> Result<Option<Error>> {
+        take_error(self.inner.as_raw())
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        set_ttl(self.inner.as_raw(), ttl)
+    }
+
+    pub fn ttl(&self) -> Result<u32> {
+        ttl(self.inner.as_raw())
+    }
+
+    pub fn set_broadcast(&self, on: bool) -> Result<()> {
+        set_broadcast(self.inner.as_raw(), on)
+    }
+
+    pub fn broadcast(&self) -> Result<bool> {
+        broadcast(self.inner.as_raw())
+    }
+
+    pub fn join_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+        join_multicast_v4(self.inner.as_raw(), multiaddr, interface)
+    }
+
+    pub fn leave_multicast_v4(&self, multiaddr: Ipv4Addr, interface: Ipv4Addr) -> Result<()> {
+        leave_multicast_v4(self.inner.as_raw(), multiaddr, interface)
+    }
+
+    pub fn set_multicast_ttl_v4(&self, ttl: u8) -> Result<()> {
+        set_multicast_ttl_v4(self.inner.as_raw(), ttl)
+    }
+
+    // Off by default. Fails with ENOPROTOOPT unless LwIP was built with LWIP_MULTICAST_TX_OPTIONS;
+    // see set_multicast_loop_v4 for the loopback netif it also relies on.
+    pub fn set_multicast_loop_v4(&self, enable: bool) -> Result<()> {
+        set_multicast_loop_v4(self.inner.as_raw(), enable)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        local_addr(self.inner.as_raw())
+    }
+
+    pub fn as_raw(&self) -> RawSocket {
+        self.inner.as_raw()
+    }
+}
+
+// A raw IP socket for ICMP or a custom protocol, e.g. RawSocketEndpoint::new(AF_INET,
+// IPPROTO_ICMP) for a pinger. Needs LwIP built with LWIP_RAW. Datagrams received on an IPv4 raw
+// socket start with the IP header; sent ones always get their header from LwIP, since callers
+// can't supply their own (see set_header_included).
+#[derive(Debug)]
+pub struct RawSocketEndpoint {
+    inner: Socket,
+}
+
+impl RawSocketEndpoint {
+    pub fn new(family: c_int, protocol: c_int) -> Result<RawSocketEndpoint> {
+        let inner = Socket::new(family, SOCK_RAW, protocol)?;
+        Ok(RawSocketEndpoint { inner })
+    }
+
+    pub fn send_to(&self, buf: &[u8], dest: IpAddr) -> Result<usize> {
+        let (to, tolen) = socket_addr_to_sockaddr(&SocketAddr::new(dest, 0))?;
+        let len = buf.len().min(i32::MAX as usize) as i32;
+        let retval = sendto(
+            self.inner.as_raw(),
+            buf.as_ptr() as *const c_void,
+            len,
+            0,
+            &to as *const sockaddr_storage as *const sockaddr,
+            tolen,
+        );
+        match retval {
+            n if n < 0 => Err(Error::last()),
+            n => Ok(n as usize),
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, IpAddr)> {
+        let mut from: sockaddr_storage = unsafe { core::mem::zeroed() };
+        let len = buf.len().min(i32::MAX as usize) as i32;
+        let (retval, fromlen) = recvfrom_storage(
+            self.inner.as_raw(),
+            buf.as_mut_ptr() as *mut c_void,
+            len,
+            0,
+            &mut from,
+        );
+        if retval < 0 {
+            return Err(Error::last());
+        }
+        let addr = sockaddr_to_socket_addr(&from, fromlen).ok_or_else(unsupported_family)?;
+        Ok((retval as usize, addr.ip()))
+    }
+
+    // Supply our own IP header on send (IP_HDRINCL). LwIP's raw API has RAW_FLAGS_HDRINCL, but the
+    // socket layer has no option to reach it, so this always fails with EOPNOTSUPP; it is here so
+    // callers can find that out without going to FFI.
+    pub fn set_header_included(&self, _included: bool) -> Result<()> {
+        Err(Error::from_errno(EOPNOTSUPP))
+    }
+
+    pub fn as_raw(&self) -> RawSocket {
+        self.inner.as_raw()
+    }
+}
+
+// Identifier/sequence source for ping, so concurrent pings from different tasks don't accept
+// each other's replies.
+static PING_SEQUENCE: AtomicU16 = AtomicU16::new(0);
+
+// Send one ICMP echo request to `dest` and wait up to `timeout` for the matching reply, returning
+// the round-trip time (millisecond resolution, from sys_now). Replies from other hosts or with a
+// different id/sequence are ignored. Fails with TimedOut if no reply arrives, and with EOPNOTSUPP
+// when LwIP lacks LWIP_RAW/LWIP_ICMP or for IPv6, which this build has no ICMPv6 for.
+pub fn ping(dest: IpAddr, timeout: Duration) -> Result<Duration> {
+    const ICMP_ECHO_REPLY: u8 = 0;
+    const ICMP_ECHO_REQUEST: u8 = 8;
+
+    if dest.is_ipv6() {
+        return Err(Error::from_errno(EOPNOTSUPP));
+    }
+    let endpoint = match RawSocketEndpoint::new(AF_INET, IPPROTO_ICMP) {
+        Ok(endpoint) => endpoint,
+        // lwip_socket rejects SOCK_RAW with EINVAL when built without LWIP_RAW.
+        Err(e) if e.errno() == Some(EINVAL) => return Err(Error::from_errno(EOPNOTSUPP)),
+        Err(e) => return Err(e),
+    };
+
+    let seq = PING_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let id = unsafe { sys_now() } as u16;
+    let mut request = [0u8; 16];
+    request[0] = ICMP_ECHO_REQUEST;
+    request[4..6].copy_from_slice(&id.to_be_bytes());
+    request[6..8].copy_from_slice(&seq.to_be_bytes());
+    request[8..].copy_from_slice(b"lwip-rs\0");
+    let checksum = icmp_checksum(&request);
+    request[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let start = unsafe { sys_now() };
+    let limit = timeout.as_millis().min(u32::MAX as u128) as u32;
+    endpoint.send_to(&request, dest)?;
+
+    let mut reply = [0u8; 128];
+    loop {
+        let elapsed = unsafe { sys_now() }.wrapping_sub(start);
+        if elapsed >= limit {
+            return Err(Error::from_errno(ETIMEDOUT));
+        }
+        let mut fds = [pollfd {
+            fd: endpoint.as_raw(),
+            events: POLLIN,
+            revents: 0,
+        }];
+        let wait = Duration::from_millis((limit - elapsed) as u64);
+        if poll_fds(&mut fds, Some(wait))? == 0 {
+            continue;
+        }
+        let (n, from) = endpoint.recv_from(&mut reply)?;
+        // The IPv4 header comes first on a raw socket; skip it by its IHL.
+        let header_len = ((reply[0] & 0x0f) as usize) * 4;
+        if from != dest || n < header_len + 8 {
+            continue;
+        }
+        let icmp = &reply[header_len..n];
+        if icmp[0] == ICMP_ECHO_REPLY
+            && icmp[4..6] == id.to_be_bytes()
+            && icmp[6..8] == seq.to_be_bytes()
+        {
+            let rtt = unsafe { sys_now() }.wrapping_sub(start);
+            return Ok(Duration::from_millis(rtt as u64));
+        }
+    }
+}
+
+// RFC 1071 Internet checksum.
+fn icmp_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+            [hi] => u16::from_be_bytes([*hi, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// A listening TCP socket.
+#[derive(Debug)]
+pub struct TcpListener {
+    inner: Socket,
+}
+
+impl TcpListener {
+    pub fn bind(addr: SocketAddr, backlog: c_int) -> Result<TcpListener> {
+        TcpListener::bind_with(addr, backlog, false)
+    }
+
+    // As bind, optionally setting SO_REUSEADDR first (see set_reuse_address) so a restarted
+    // service can take its port straight back.
+    pub fn bind_with(addr: SocketAddr, backlog: c_int, reuse_address: bool) -> Result<TcpListener> {
+        TcpListener::bind_configured(addr, backlog, |sock| {
+            if reuse_address {
+                set_reuse_address(sock, true)?;
+            }
+            Ok(())
+        })
+    }
+
+    // As bind, setting IPV6_V6ONLY first (see set_only_v6): LwIP only reads the flag at bind, so
+    // this is the one place it can be chosen for a listener.
+    #[cfg(feature = "ipv6")]
+    pub fn bind_only_v6(addr: SocketAddr, backlog: c_int, only: bool) -> Result<TcpListener> {
+        TcpListener::bind_configured(addr, backlog, |sock| set_only_v6(sock, only))
+    }
+
+    fn bind_configured(
+        addr: SocketAddr,
+        backlog: c_int,
+        configure: impl FnOnce(RawSocket) -> Result<()>,
+    ) -> Result<TcpListener> {
+        let inner = Socket::for_addr(&addr, SOCK_STREAM, IPPROTO_TCP)?;
+        configure(inner.as_raw())?;
+        let (name, namelen) = socket_addr_to_sockaddr(&addr)?;
+        let retval = bind(
+            inner.as_raw(),
+            &name as *const sockaddr_storage as *const sockaddr,
+            namelen,
+        );
+        if retval != 0 {
+            return Err(Error::last());
+        }
+        if listen(inner.as_raw(), backlog) != 0 {
+            return Err(Error::last());
+        }
+        Ok(TcpListener { inner })
+    }
+
+    // Wait for (or, on a non-blocking listener, check for - failing with WouldBlock) the next
+    // incoming connection.
+    pub fn accept(&self) -> Result<(TcpStream, SocketAddr)> {
+        let mut peer: sockaddr_storage = unsafe { core::mem::zeroed() };
+        let mut peer_len = core::mem::size_of::<sockaddr_storage>() as socklen_t;
+        let sock = accept(
+            self.inner.as_raw(),
+            &mut peer as *mut sockaddr_storage as *mut sockaddr,
+            &mut peer_len,
+        );
+        if sock < 0 {
+            return Err(Error::last());
+        }
+        let stream = TcpStream {
+            inner: unsafe { Socket::from_raw(sock) },
+        };
+        let addr = sockaddr_to_socket_addr(&peer, peer_len).ok_or_else(unsupported_family)?;
+        Ok((stream, addr))
+    }
+
+    // An endless iterator over accepted connections.
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    // Always fails with EOPNOTSUPP: the listener is already bound and LwIP only consults the
+    // flag at bind. Use bind_only_v6 instead.
+    #[cfg(feature = "ipv6")]
+    pub fn set_only_v6(&self, _only: bool) -> Result<()> {
+        Err(Error::from_errno(EOPNOTSUPP))
+    }
+
+    #[cfg(feature = "ipv6")]
+    pub fn only_v6(&self) -> Result<bool> {
+        only_v6(self.inner.as_raw())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        local_addr(self.inner.as_raw())
+    }
+
+    pub fn as_raw(&self) -> RawSocket {
+        self.inner.as_raw()
+    }
+}
+
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<TcpStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_dump_empty() {
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    #[test]
+    fn hex_dump_partial_line() {
+        assert_eq!(
+            hex_dump(b"abc"),
+            "0x0000:  6162 63                                  abc\n"
+        );
+    }
+
+    #[test]
+    fn hex_dump_multi_line() {
+        assert_eq!(
+            hex_dump(b"GET / HTTP/1.1\r\nHost: x\r\n"),
+            "0x0000:  4745 5420 2f20 4854 5450 2f31 2e31 0d0a  GET / HTTP/1.1..\n\
+             0x0010:  486f 7374 3a20 780d 0a                   Host: x..\n"
+        );
+    }
+
+    #[test]
+    fn timer_wheel_expires_in_deadline_order() {
+        let mut wheel: TimerWheel<u32> = TimerWheel::new_at(1_000);
+        wheel.register_at(1, 1_300, 1_000);
+        wheel.register_at(2, 1_100, 1_000);
+        wheel.register_at(3, 1_200, 1_000);
+        assert_eq!(wheel.poll_timeout_at(1_000), 100);
+        assert!(wheel.expired_at(1_099).is_empty());
+        assert_eq!(wheel.expired_at(1_250), vec![2, 3]);
+        assert_eq!(wheel.poll_timeout_at(1_250), 50);
+        assert_eq!(wheel.expired_at(1_300), vec![1]);
+        assert_eq!(wheel.poll_timeout_at(1_300), -1);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn timer_wheel_rearm_and_cancel() {
+        let mut wheel: TimerWheel<u32> = TimerWheel::new_at(0);
+        wheel.register_at(1, 100, 0);
+        wheel.register_at(2, 200, 0);
+        wheel.register_at(1, 300, 0);
+        assert_eq!(wheel.len(), 2);
+        assert!(wheel.cancel(2));
+        assert!(!wheel.cancel(2));
+        assert!(wheel.expired_at(200).is_empty());
+        assert_eq!(wheel.expired_at(300), vec![1]);
+    }
+
+    #[test]
+    fn timer_wheel_survives_sys_now_wraparound() {
+        let start = u32::MAX - 50;
+        let mut wheel: TimerWheel<u32> = TimerWheel::new_at(start);
+        wheel.register_at(1, start.wrapping_add(100), start);
+        wheel.register_at(2, start.wrapping_add(20), start);
+        assert_eq!(wheel.poll_timeout_at(start), 20);
+        assert_eq!(wheel.expired_at(start.wrapping_add(30)), vec![2]);
+        assert_eq!(wheel.poll_timeout_at(start.wrapping_add(30)), 70);
+        assert_eq!(wheel.expired_at(start.wrapping_add(100)), vec![1]);
+    }
+
+    #[test]
+    fn timer_wheel_past_deadline_is_due_immediately() {
+        let mut wheel: TimerWheel<u32> = TimerWheel::new_at(500);
+        wheel.register_at(1, 400, 500);
+        assert_eq!(wheel.poll_timeout_at(500), 0);
+        assert_eq!(wheel.expired_at(500), vec![1]);
+    }
+
+    #[test]
+    fn sockaddr_v4_round_trip() {
+        let addr: SocketAddr = "192.168.1.20:8080".parse().unwrap();
+        let (storage, len) = socket_addr_to_sockaddr(&addr).unwrap();
+        assert_eq!(len as usize, core::mem::size_of::<sockaddr_in>());
+        let sin: sockaddr_in = unsafe { read_sockaddr(&storage) };
+        assert_eq!(sin.sin_port, 8080u16.to_be());
+        assert_eq!(
+            sin.sin_addr.s_addr,
+            u32::from(Ipv4Addr::new(192, 168, 1, 20)).to_be()
+        );
+        assert_eq!(sockaddr_to_socket_addr(&storage, len), Some(addr));
+    }
+
+    #[test]
+    fn sockaddr_rejects_short_length() {
+        let addr: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let (storage, len) = socket_addr_to_sockaddr(&addr).unwrap();
+        assert_eq!(sockaddr_to_socket_addr(&storage, len - 1), None);
+        assert_eq!(sockaddr_to_socket_addr(&storage, 0), None);
+    }
+
+    #[cfg(not(feature = "ipv6"))]
+    #[test]
+    fn sockaddr_v6_needs_ipv6_feature() {
+        let addr: SocketAddr = "[::1]:80".parse().unwrap();
+        assert_eq!(
+            socket_addr_to_sockaddr(&addr).unwrap_err().errno(),
+            Some(EAFNOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn errno_kind_table() {
+        let table = [
+            (ECONNREFUSED, ErrorKind::ConnectionRefused),
+            (ECONNRESET, ErrorKind::ConnectionReset),
+            (ECONNABORTED, ErrorKind::ConnectionAborted),
+            (ENOTCONN, ErrorKind::NotConnected),
+            (ETIMEDOUT, ErrorKind::TimedOut),
+            (EADDRINUSE, ErrorKind::AddrInUse),
+            (EADDRNOTAVAIL, ErrorKind::AddrNotAvailable),
+            (EWOULDBLOCK, ErrorKind::WouldBlock),
+            (EINTR, ErrorKind::Interrupted),
+            (EPIPE, ErrorKind::BrokenPipe),
+            (EINVAL, ErrorKind::InvalidInput),
+            (ENOENT, ErrorKind::NotFound),
+            (ENOMEM, ErrorKind::OutOfMemory),
+            (ENOBUFS, ErrorKind::OutOfMemory),
+            (EOPNOTSUPP, ErrorKind::Unsupported),
+            (ENOPROTOOPT, ErrorKind::Unsupported),
+            (EAFNOSUPPORT, ErrorKind::Unsupported),
+            (EIO, ErrorKind::Other),
+        ];
+        for (errno, kind) in table {
+            assert_eq!(ErrorKind::from_errno(errno), kind, "errno {}", errno);
+            assert_eq!(Error::from_errno(errno).kind(), kind, "errno {}", errno);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn errno_to_io_error_keeps_kind_and_errno() {
+        for errno in [ECONNRESET, EWOULDBLOCK, EOPNOTSUPP, EIO] {
+            let err = errno_to_io_error(errno);
+            assert_eq!(err.kind(), errno_to_error_kind(errno), "errno {}", errno);
+            let kept = err.raw_os_error().or_else(|| {
+                err.get_ref()
+                    .and_then(|e| e.downcast_ref::<LwipErrno>())
+                    .map(|e| e.0)
+            });
+            assert_eq!(kept, Some(errno));
+        }
+    }
+
+    #[test]
+    fn icmp_checksum_rfc1071_example() {
+        // The worked example in RFC 1071 section 3: the one's complement sum is 0xddf2.
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(icmp_checksum(&data), !0xddf2);
+    }
+
+    #[test]
+    fn icmp_checksum_odd_length_pads_with_zero() {
+        assert_eq!(icmp_checksum(&[0x01]), !0x0100);
+        assert_eq!(icmp_checksum(&[0x00, 0x01, 0x02]), !0x0201);
+    }
+
+    #[test]
+    fn icmp_checksum_verifies_to_zero() {
+        let mut packet = [
+            8, 0, 0, 0, 0x12, 0x34, 0x00, 0x01, b'p', b'i', b'n', b'g', b'!',
+        ];
+        let sum = icmp_checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+        assert_eq!(icmp_checksum(&packet), 0);
+    }
+
+    #[test]
+    fn rate_limiter_refills_at_rate_up_to_burst() {
+        // 1000 bytes/s is one byte per millisecond.
+        let mut limiter = RateLimiter::new_at(1_000, 500, 0);
+        assert_eq!(limiter.available_at(0), 500);
+        limiter.consume(500);
+        assert_eq!(limiter.available_at(0), 0);
+        assert_eq!(limiter.available_at(200), 200);
+        limiter.consume(150);
+        assert_eq!(limiter.available_at(300), 150);
+        assert_eq!(limiter.available_at(10_000), 500);
+    }
+
+    #[test]
+    fn rate_limiter_keeps_fractional_refills() {
+        // 10 bytes/s: a byte every 100 ms, accumulated across many short intervals.
+        let mut limiter = RateLimiter::new_at(10, 100, 0);
+        limiter.consume(100);
+        for now in (10..=250).step_by(10) {
+            limiter.available_at(now);
+        }
+        assert_eq!(limiter.available_at(250), 2);
+    }
+
+    #[test]
+    fn rate_limiter_refill_survives_sys_now_wraparound() {
+        let start = u32::MAX - 99;
+        let mut limiter = RateLimiter::new_at(1_000, 1_000, start);
+        limiter.consume(1_000);
+        assert_eq!(limiter.available_at(start.wrapping_add(300)), 300);
+    }
+
+    #[test]
+    fn rate_limiter_with_zero_rate_never_refills() {
+        let mut limiter = RateLimiter::new_at(0, 100, 0);
+        limiter.consume(100);
+        assert_eq!(limiter.available_at(1_000_000), 0);
+    }
+
+    // recv_io/send_io surface failures through From<Error> for io::Error.
+    #[cfg(feature = "std")]
+    #[test]
+    fn io_conversion_keeps_would_block_and_connection_reset() {
+        let would_block: std::io::Error = Error::from_errno(EWOULDBLOCK).into();
+        assert_eq!(would_block.kind(), std::io::ErrorKind::WouldBlock);
+        let reset: std::io::Error = Error::from_errno(ECONNRESET).into();
+        assert_eq!(reset.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    // The registry side of shutdown_all; opening real sockets needs a running LwIP stack.
+    #[test]
+    fn socket_registry_only_lists_sockets_that_are_still_open() {
+        let mut registry = SocketRegistry::new();
+        let ids: Vec<u32> = (10..13).map(|sock| registry.register(sock)).collect();
+        assert!(!ids.contains(&0));
+
+        // Dropped before shutdown_all: its Drop removes the entry, so shutdown_all skips it.
+        assert_eq!(registry.deregister(ids[0]), Some(10));
+
+        let mut open = registry.open_sockets();
+        open.sort_unstable();
+        assert_eq!(open, [11, 12]);
+
+        // shutdown_all leaves the entries to the handles' own Drop.
+        assert_eq!(registry.deregister(ids[1]), Some(11));
+        assert_eq!(registry.deregister(ids[2]), Some(12));
+        assert_eq!(registry.deregister(ids[0]), None);
+        assert!(registry.open_sockets().is_empty());
+    }
+
+    #[test]
+    fn socket_registry_ids_skip_zero_and_live_entries() {
+        let mut registry = SocketRegistry::new();
+        registry.next_id = u32::MAX;
+        let live = registry.register(1);
+        assert_eq!(live, u32::MAX);
+        assert_eq!(registry.register(2), 1);
+        registry.next_id = u32::MAX;
+        assert_eq!(registry.register(3), 2);
+        assert_eq!(registry.deregister(live), Some(1));
+    }
+
+    // A stand-in TLS library for the TlsBackend seam: the "handshake" sends the server name and
+    // waits for a one-byte reply, after which traffic is XORed with that byte.
+    #[cfg(feature = "std")]
+    struct StubTls;
+
+    #[cfg(feature = "std")]
+    struct StubSession {
+        stream: Box<dyn ReadWrite>,
+        key: u8,
+    }
+
+    #[cfg(feature = "std")]
+    impl std::io::Read for StubSession {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.stream.read(buf)?;
+            buf[..n].iter_mut().for_each(|b| *b ^= self.key);
+            Ok(n)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::io::Write for StubSession {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let sealed: Vec<u8> = buf.iter().map(|b| b ^ self.key).collect();
+            self.stream.write(&sealed)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl TlsBackend for StubTls {
+        fn handshake(
+            &self,
+            mut stream: Box<dyn ReadWrite>,
+            domain: &str,
+        ) -> std::io::Result<Box<dyn ReadWrite>> {
+            use std::io::{Read, Write};
+            stream.write_all(domain.as_bytes())?;
+            let mut key = [0u8];
+            stream.read_exact(&mut key)?;
+            Ok(Box::new(StubSession {
+                stream,
+                key: key[0],
+            }))
+        }
+    }
+
+    // The server end of an in-memory connection, behaving like a non-blocking socket: reading
+    // with nothing queued fails with WouldBlock. Shared so the test can still reach it once the
+    // client end has been boxed.
+    #[cfg(feature = "std")]
+    #[derive(Clone, Default)]
+    struct StubPeer(Arc<Mutex<(std::collections::VecDeque<u8>, Vec<u8>)>>);
+
+    #[cfg(feature = "std")]
+    impl std::io::Read for StubPeer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut peer = self.0.lock().unwrap();
+            if peer.0.is_empty() {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(peer.0.len());
+            for (dst, src) in buf.iter_mut().zip(peer.0.drain(..n)) {
+                *dst = src;
+            }
+            Ok(n)
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl std::io::Write for StubPeer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().1.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn tls_backend_wraps_the_stream_it_is_handed() {
+        use std::io::{Read, Write};
+        let peer = StubPeer::default();
+        peer.0.lock().unwrap().0.push_back(0x55);
+        let mut secure = connect_tls_with(peer.clone(), "device.example", &StubTls).unwrap();
+        assert_eq!(peer.0.lock().unwrap().1, b"device.example");
+
+        peer.0.lock().unwrap().1.clear();
+        secure.write_all(&[0x00, 0xff]).unwrap();
+        assert_eq!(peer.0.lock().unwrap().1, [0x55, 0xaa]);
+
+        peer.0.lock().unwrap().0.extend([0x54, 0x56]);
+        let mut reply = [0u8; 2];
+        secure.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [0x01, 0x03]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn tls_backend_sees_would_block_from_a_non_blocking_stream() {
+        let err = connect_tls_with(StubPeer::default(), "device.example", &StubTls)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn listen_backlog_is_clamped_like_lwip() {
+        assert_eq!(
+            effective_backlog(-1).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(effective_backlog(0).unwrap(), 1);
+        assert_eq!(effective_backlog(1).unwrap(), 1);
+        assert_eq!(effective_backlog(255).unwrap(), 255);
+        assert_eq!(effective_backlog(256).unwrap(), 255);
+        assert_eq!(effective_backlog(c_int::MAX).unwrap(), 255);
+    }
 }
@@ -1395,7 +1395,7 @@ extern "C" {
 }
 extern "C" {
     pub fn lwip_poll(
-        fds: *const pollfd,
+        fds: *mut pollfd,
         nfds: nfds_t,
         timeout: core::ffi::c_int,
     ) -> core::ffi::c_int;